@@ -0,0 +1,242 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+use zeroize::Zeroize;
+
+use crate::backend;
+
+pub const PRIVATE_KEY_SIZE: usize = 32;
+pub const PUBLIC_KEY_SIZE: usize = 64;
+pub const SHARED_SECRET_SIZE: usize = 32;
+
+/// 密钥解析失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// 奇数长度或非 hex 字符
+    InvalidHex(String),
+    InvalidLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// 私钥不在 `[1, n)` 范围内
+    OutOfRange,
+    /// 公钥不是曲线上的点
+    NotOnCurve,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::InvalidHex(reason) => f.write_str(reason),
+            KeyError::InvalidLength { expected, actual } => {
+                write!(f, "expected {} bytes, got {}", expected, actual)
+            }
+            KeyError::OutOfRange => f.write_str("out of range for secp256k1"),
+            KeyError::NotOnCurve => f.write_str("not a secp256k1 public key"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+fn decode_hex<const N: usize>(s: &str) -> Result<[u8; N], KeyError> {
+    let s = s.trim();
+    if s.len() % 2 == 1 {
+        return Err(KeyError::InvalidHex(format!("odd hex length {}", s.len())));
+    }
+    if let Some(c) = s.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(KeyError::InvalidHex(format!(
+            "invalid hex character {:?}",
+            c
+        )));
+    }
+    if s.len() != N * 2 {
+        return Err(KeyError::InvalidLength {
+            expected: N,
+            actual: s.len() / 2,
+        });
+    }
+    let mut buf = [0; N];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(buf)
+}
+
+fn encode_hex(buf: &[u8]) -> String {
+    buf.iter().map(|v| format!("{:02x}", *v)).collect()
+}
+
+fn to_array<const N: usize>(buf: &[u8]) -> Result<[u8; N], KeyError> {
+    buf.try_into().map_err(|_| KeyError::InvalidLength {
+        expected: N,
+        actual: buf.len(),
+    })
+}
+
+/// secp256k1 私钥, 32 字节, 保证在 `[1, n)` 范围内, drop 时清零
+#[derive(Clone, PartialEq, Eq)]
+pub struct PrivateKey([u8; PRIVATE_KEY_SIZE]);
+
+impl PrivateKey {
+    pub fn from_bytes(buf: &[u8]) -> Result<PrivateKey, KeyError> {
+        let key = PrivateKey(to_array(buf)?);
+        if !key.is_valid() {
+            return Err(KeyError::OutOfRange);
+        }
+        Ok(key)
+    }
+
+    pub fn from_hex(s: &str) -> Result<PrivateKey, KeyError> {
+        let key = PrivateKey(decode_hex(s)?);
+        if !key.is_valid() {
+            return Err(KeyError::OutOfRange);
+        }
+        Ok(key)
+    }
+
+    fn is_valid(&self) -> bool {
+        let mut public_key = [0; PUBLIC_KEY_SIZE];
+        backend::compute_public_key(&self.0, &mut public_key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PRIVATE_KEY_SIZE] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.0)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut public_key = [0; PUBLIC_KEY_SIZE];
+        // 构造时已经检查过范围, 一定能算出公钥
+        assert!(backend::compute_public_key(&self.0, &mut public_key));
+        PublicKey(public_key)
+    }
+
+    /// ECDH, 共享密钥为 X 坐标
+    pub fn shared_secret(&self, public_key: &PublicKey) -> SharedSecret {
+        let mut secret = SharedSecret([0; SHARED_SECRET_SIZE]);
+        // 双方的密钥都已检查过, 一定能算出共享密钥
+        assert!(backend::shared_secret(
+            &public_key.0,
+            &self.0,
+            &mut secret.0
+        ));
+        secret
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<PrivateKey, KeyError> {
+        PrivateKey::from_hex(s)
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// secp256k1 公钥, 64 字节的 X || Y, 保证是曲线上的点
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; PUBLIC_KEY_SIZE]);
+
+impl PublicKey {
+    pub fn from_bytes(buf: &[u8]) -> Result<PublicKey, KeyError> {
+        PublicKey::from_array(to_array(buf)?)
+    }
+
+    pub fn from_array(buf: [u8; PUBLIC_KEY_SIZE]) -> Result<PublicKey, KeyError> {
+        if !backend::valid_public_key(&buf) {
+            return Err(KeyError::NotOnCurve);
+        }
+        Ok(PublicKey(buf))
+    }
+
+    pub fn from_hex(s: &str) -> Result<PublicKey, KeyError> {
+        PublicKey::from_array(decode_hex(s)?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        encode_hex(&self.0)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<PublicKey, KeyError> {
+        PublicKey::from_hex(s)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.to_hex())
+    }
+}
+
+/// ECDH 的共享密钥, drop 时清零
+pub struct SharedSecret([u8; SHARED_SECRET_SIZE]);
+
+impl SharedSecret {
+    pub fn as_bytes(&self) -> &[u8; SHARED_SECRET_SIZE] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedSecret(..)")
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// 密钥对
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPair {
+    pub private_key: PrivateKey,
+    pub public_key: PublicKey,
+}
+
+impl KeyPair {
+    /// 生成新的密钥对, 随机数源不可用时返回 `None`
+    pub fn generate() -> Option<KeyPair> {
+        let mut private_key = PrivateKey([0; PRIVATE_KEY_SIZE]);
+        let mut public_key = PublicKey([0; PUBLIC_KEY_SIZE]);
+        if !backend::make_key(&mut private_key.0, &mut public_key.0) {
+            return None;
+        }
+        Some(KeyPair {
+            private_key,
+            public_key,
+        })
+    }
+}
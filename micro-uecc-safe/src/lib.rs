@@ -1,81 +1,238 @@
-use micro_uecc_sys;
-
-pub fn uecc_mkae_key_with_secp2561k1() -> Option<(String, String)> {
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256k1();
-        let prlen = micro_uecc_sys::uECC_curve_private_key_size(curve) as usize;
-        let plen = micro_uecc_sys::uECC_curve_public_key_size(curve) as usize;
-        let mut private_key_buf = vec![0; prlen];
-        let mut public_key_buf = vec![0; plen];
-        let ret =
-            micro_uecc_sys::uECC_make_key(&mut public_key_buf[0], &mut private_key_buf[0], curve);
-        if ret != 1 {
-            assert!(false, "生成失败");
-            return None;
-        }
-        let private_key = private_key_buf
-            .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)?;
-
-        let public_key = public_key_buf
-            .iter()
-            .map(|v| format!("{:02x}", *v))
-            .reduce(|cur, next| cur + &next)?;
-
-        assert!(private_key.len() > 0, "生成失败");
-        assert!(public_key.len() > 0, "生成失败");
-        Some((private_key, public_key))
+//! secp256k1 的密钥生成及 ECDH
+//!
+//! 默认通过 FFI 使用 C 版本的 micro-ecc (feature `micro-ecc`),
+//! 关闭默认 feature 并启用 `pure-rust` 时使用纯 Rust 的 k256, 两者的接口及密钥格式相同
+//!
+//! 密钥都是固定长度的类型, 构造时检查合法性, 私钥及共享密钥 drop 时清零
+
+#[cfg(not(any(feature = "micro-ecc", feature = "pure-rust")))]
+compile_error!("micro-uecc-safe requires feature \"micro-ecc\" or \"pure-rust\"");
+
+mod key;
+#[cfg(feature = "micro-ecc")]
+pub mod micro_ecc;
+#[cfg(feature = "pure-rust")]
+pub mod pure_rust;
+
+/// 两个 feature 都启用时使用 micro-ecc
+#[cfg(feature = "micro-ecc")]
+use micro_ecc as backend;
+#[cfg(all(feature = "pure-rust", not(feature = "micro-ecc")))]
+use pure_rust as backend;
+
+pub use key::{
+    KeyError, KeyPair, PrivateKey, PublicKey, SharedSecret, PRIVATE_KEY_SIZE, PUBLIC_KEY_SIZE,
+    SHARED_SECRET_SIZE,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_pair_test() {
+        let pair = KeyPair::generate().unwrap();
+        assert_eq!(pair.private_key.public_key(), pair.public_key);
+        assert_eq!(pair.private_key.to_hex().len(), 64);
+        assert_eq!(pair.public_key.to_string().len(), 128);
+        assert_eq!(
+            pair.private_key.to_hex().parse::<PrivateKey>(),
+            Ok(pair.private_key.clone())
+        );
+        assert_eq!(
+            PublicKey::from_hex(&pair.public_key.to_hex()),
+            Ok(pair.public_key)
+        );
+        // Debug 不输出私钥
+        assert!(!format!("{:?}", pair).contains(&pair.private_key.to_hex()));
+
+        let other = KeyPair::generate().unwrap();
+        assert_eq!(
+            pair.private_key.shared_secret(&other.public_key).as_bytes(),
+            other.private_key.shared_secret(&pair.public_key).as_bytes()
+        );
     }
-}
 
-pub fn ucc_shared_secret_whith_secp2561k1(
-    pub_key_buf: &mut [u8],
-    priv_key_buf: &mut [u8],
-    ecdh_buf: &mut [u8],
-) -> Option<()> {
-    unsafe {
-        let curve = micro_uecc_sys::uECC_secp256k1();
-        let ret = micro_uecc_sys::uECC_shared_secret(
-            &mut pub_key_buf[0],
-            &mut priv_key_buf[0],
-            &mut ecdh_buf[0],
-            curve,
+    #[test]
+    fn key_error_test() {
+        let pair = KeyPair::generate().unwrap();
+        let mut invalid_public_key = *pair.public_key.as_bytes();
+        invalid_public_key[63] ^= 1;
+        assert_eq!(
+            PublicKey::from_array(invalid_public_key),
+            Err(KeyError::NotOnCurve)
+        );
+        assert_eq!(
+            PublicKey::from_bytes(&[0; 32]),
+            Err(KeyError::InvalidLength {
+                expected: 64,
+                actual: 32
+            })
+        );
+
+        // 0 及大于等于曲线阶 n 的私钥都不合法
+        assert_eq!(PrivateKey::from_bytes(&[0; 32]), Err(KeyError::OutOfRange));
+        assert_eq!(
+            PrivateKey::from_bytes(&[0xff; 32]),
+            Err(KeyError::OutOfRange)
+        );
+        assert_eq!(
+            PrivateKey::from_bytes(&pair.private_key.as_bytes()[..31]),
+            Err(KeyError::InvalidLength {
+                expected: 32,
+                actual: 31
+            })
+        );
+        let mut one = [0; 32];
+        one[31] = 1;
+        assert!(PrivateKey::from_bytes(&one).is_ok());
+
+        assert_eq!(
+            PrivateKey::from_hex("abc").unwrap_err().to_string(),
+            "odd hex length 3"
+        );
+        assert_eq!(
+            PrivateKey::from_hex("zz").unwrap_err().to_string(),
+            "invalid hex character 'z'"
+        );
+        assert_eq!(
+            PrivateKey::from_hex("abcd").unwrap_err().to_string(),
+            "expected 32 bytes, got 2"
         );
-        if ret == 1 {
-            Some(())
-        } else {
-            None
-        }
     }
-}
 
-pub struct UEcckeyPair {
-    pub private_key: String,
-    pub public_key: String,
-}
+    /// `sample_data/custom.env` 中的私钥及公钥
+    fn sample_keys() -> (PrivateKey, PublicKey) {
+        let path =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../sample_data/custom.env");
+        let content = std::fs::read_to_string(path).unwrap();
+        let value = |name: &str| {
+            content
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(name)?.trim().strip_prefix('='))
+                .map(|it| it.trim().trim_matches('"').to_string())
+                .unwrap()
+        };
+        (
+            value("TEST_XLOG_PRIVATE_KEY").parse().unwrap(),
+            value("TEST_XLOG_PUBLIC_KEY").parse().unwrap(),
+        )
+    }
 
-pub fn gen_secp2561k1_key_pair() -> Option<UEcckeyPair> {
-    match uecc_mkae_key_with_secp2561k1() {
-        None => None,
-        Some((private_key, public_key)) => Some(UEcckeyPair {
-            private_key,
-            public_key,
-        }),
+    #[test]
+    fn sample_key_test() {
+        let (private_key, public_key) = sample_keys();
+        assert_eq!(private_key.public_key(), public_key);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// `sample_data/*_crypt_20220110.xlog` 中 mars 生成的 client 公钥
+    #[cfg(all(feature = "micro-ecc", feature = "pure-rust"))]
+    const SAMPLE_CLIENT_PUBLIC_KEYS: [&str; 16] = [
+        "0e08a95177093d2c32274e1f822d41369919fdd4a869054f055dfe6c1d93f90fbc4f505d9feeb3ab8ae937e4d3ddaab1d5ebb42b375737f5b755e33951bb1c3d",
+        "25964224086a6fb39a8b9da8ddbf3e2252c3d7412464640c9805052f96004792459e93cb428577755c8d5367fb3e40cd00796921d26c39019c9cf9fbfb185c6e",
+        "28ddd737cf55822a2552a6b5e407522d07d4a0d996f1f6431459814e872b31c22c91ba5abcda335026174eaaaeb28c0e46b83846248ad2c099d8a1b13f2a6fec",
+        "33711059dc853e93b26b416ded77fc416785c53c75e40130884dff80895eb8ef2a638f07e33e853cb347c8c3500acdedb42e0afbb17cd0230700b310fcdd626c",
+        "3d87e1110e0b401a34b1880da2175e3bc92936e10a55ee62e71903982c715fcfc2655d6fb5324b9128fdc49e3618e12e42b1b0122f58e4481ca45efdcc3299e7",
+        "414409279eb6130dfc4d8892e33513c6f8960b3402d340947d0bb6c5b74a90a18f3f58473f8b410099db3853214a94b110afb75e92dbeac5efdf3768ac14e682",
+        "47f382a52eee950e19fe4394d275a9c9572316774a8d595763aea1617d41a2f13ad1f2e4ab537e1498a21ccd2e59e36db4e75c58c7e376649ab377c7b5999b29",
+        "5e7edcec503835e7aaaf30d86c569ebf9016e74c1e6ef23db8452d231eb3f8c9c52d9aca2bf7ef2af0567c1d17d3de2c90f3ff474c5ffe73c54710d7f652db2b",
+        "694fcf477d39e8cfb2dcd38b8c76b5ccdfdcd19592cbf7e2d119198ba75ace5231be2d494a8b0f0beaf08f368114ae3ba2fe5d5310242f62392aff459fc28dee",
+        "8977ea541187c46002a113d35058388f29febf28d42e69bc82aece34989399a1cd127d83d9144f37b08c14683f7c266b7f24b7b1babeb191250f0b5cc71b13db",
+        "9a42d31cb5ffa7ffd0cdbf8e25fbb8976e0ddd189da2457043aeacdc3fb9392a36a4ad0875c184460492853060d9cdf9604db4166083271521738993861c85ef",
+        "9d1e5384ba575f70e067502c648fbb89eb079eca7dc068133eba0ccce550b5408476958c3a2352c97d25a14797acd3116b85d3004efa92e65f233a71be5ccc58",
+        "9f6bd085746ae7990e5808f9b38073d69d61b982ffba852781f634b4c6ce4f49bdca849e1b0395a3d4b82de8126d4504d99c986809f827893d6f2f361faeba9b",
+        "c0418ed18b510d8206daf8f8ead7085bb88d22aa1ef176a77381ce3db00a25714ccf744f13dc3531610392509504bb565f582e73fac87a07d10eba4820b717a7",
+        "f5b20b658a25c668377632935466e397e9f7d6776039e3bffaac2a2babfcfb5738970bcd0b2147a11315db4e50448b8c8b710acd2d1933b370a2a486f404107f",
+        "f8c2b762bf1e2ed27b8f7c554d2d355423faee115c9d0dc6fedb23bb0353b27e25f33ed968aaf78af9e83c0580298f0a5cecf74d5fe3dc72ba20a674fc7f2113",
+    ];
+
+    /// 两个后端对同一组密钥的计算结果必须完全相同
+    #[cfg(all(feature = "micro-ecc", feature = "pure-rust"))]
     #[test]
-    fn gen_secp2561k1_key_pair() {
-        match uecc_mkae_key_with_secp2561k1() {
-            None => println!("生成失败"),
-            Some((private_key, public_key)) => {
-                println!("private_key: {}", private_key);
-                println!("public_key: {}", public_key);
+    fn backend_cross_check_test() {
+        let (sample_private_key, sample_public_key) = sample_keys();
+        let mut pairs = vec![(
+            *sample_private_key.as_bytes(),
+            *sample_public_key.as_bytes(),
+        )];
+        for _ in 0..8 {
+            let mut pair = ([0; 32], [0; 64]);
+            assert!(micro_ecc::make_key(&mut pair.0, &mut pair.1));
+            pairs.push(pair);
+            let mut pair = ([0; 32], [0; 64]);
+            assert!(pure_rust::make_key(&mut pair.0, &mut pair.1));
+            pairs.push(pair);
+        }
+
+        for (private_key, public_key) in &pairs {
+            let mut micro_ecc_public_key = [0; 64];
+            let mut pure_rust_public_key = [0; 64];
+            assert!(micro_ecc::compute_public_key(
+                private_key,
+                &mut micro_ecc_public_key
+            ));
+            assert!(pure_rust::compute_public_key(
+                private_key,
+                &mut pure_rust_public_key
+            ));
+            assert_eq!(&micro_ecc_public_key, public_key);
+            assert_eq!(&pure_rust_public_key, public_key);
+            assert!(micro_ecc::valid_public_key(public_key));
+            assert!(pure_rust::valid_public_key(public_key));
+        }
+
+        for (private_key, _) in &pairs {
+            for (_, public_key) in &pairs {
+                let mut micro_ecc_secret = [0; 32];
+                let mut pure_rust_secret = [0; 32];
+                assert!(micro_ecc::shared_secret(
+                    public_key,
+                    private_key,
+                    &mut micro_ecc_secret
+                ));
+                assert!(pure_rust::shared_secret(
+                    public_key,
+                    private_key,
+                    &mut pure_rust_secret
+                ));
+                assert_eq!(micro_ecc_secret, pure_rust_secret);
             }
-        };
+        }
+
+        // 与 sample 私钥对 mars 生成的真实 client 公钥协商
+        for client_public_key in &SAMPLE_CLIENT_PUBLIC_KEYS {
+            let client_public_key = PublicKey::from_hex(client_public_key).unwrap();
+            assert!(micro_ecc::valid_public_key(client_public_key.as_bytes()));
+            assert!(pure_rust::valid_public_key(client_public_key.as_bytes()));
+            let mut micro_ecc_secret = [0; 32];
+            let mut pure_rust_secret = [0; 32];
+            assert!(micro_ecc::shared_secret(
+                client_public_key.as_bytes(),
+                sample_private_key.as_bytes(),
+                &mut micro_ecc_secret
+            ));
+            assert!(pure_rust::shared_secret(
+                client_public_key.as_bytes(),
+                sample_private_key.as_bytes(),
+                &mut pure_rust_secret
+            ));
+            assert_eq!(micro_ecc_secret, pure_rust_secret);
+        }
+
+        let mut invalid_public_key = pairs[0].1;
+        invalid_public_key[63] ^= 1;
+        assert!(!micro_ecc::valid_public_key(&invalid_public_key));
+        assert!(!pure_rust::valid_public_key(&invalid_public_key));
+        for private_key in [[0; 32], [0xff; 32]] {
+            let mut public_key = [0; 64];
+            assert!(!micro_ecc::compute_public_key(
+                &private_key,
+                &mut public_key
+            ));
+            assert!(!pure_rust::compute_public_key(
+                &private_key,
+                &mut public_key
+            ));
+        }
     }
 }
@@ -0,0 +1,58 @@
+//! C 版本的 micro-ecc, 通过 micro-uecc-sys 调用
+//!
+//! 缓冲区都是固定长度的数组, 与 `uECC_secp256k1` 的私钥及公钥长度一致
+
+use micro_uecc_sys;
+
+use crate::key::{PRIVATE_KEY_SIZE, PUBLIC_KEY_SIZE, SHARED_SECRET_SIZE};
+
+/// 生成密钥对
+pub fn make_key(
+    private_key: &mut [u8; PRIVATE_KEY_SIZE],
+    public_key: &mut [u8; PUBLIC_KEY_SIZE],
+) -> bool {
+    unsafe {
+        let curve = micro_uecc_sys::uECC_secp256k1();
+        micro_uecc_sys::uECC_make_key(public_key.as_mut_ptr(), private_key.as_mut_ptr(), curve) == 1
+    }
+}
+
+/// ECDH, 共享密钥为 X 坐标
+pub fn shared_secret(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    private_key: &[u8; PRIVATE_KEY_SIZE],
+    secret: &mut [u8; SHARED_SECRET_SIZE],
+) -> bool {
+    unsafe {
+        let curve = micro_uecc_sys::uECC_secp256k1();
+        micro_uecc_sys::uECC_shared_secret(
+            public_key.as_ptr(),
+            private_key.as_ptr(),
+            secret.as_mut_ptr(),
+            curve,
+        ) == 1
+    }
+}
+
+/// 检查公钥是否为 secp256k1 曲线上的合法点
+pub fn valid_public_key(public_key: &[u8; PUBLIC_KEY_SIZE]) -> bool {
+    unsafe {
+        let curve = micro_uecc_sys::uECC_secp256k1();
+        micro_uecc_sys::uECC_valid_public_key(public_key.as_ptr(), curve) == 1
+    }
+}
+
+/// 由私钥计算公钥, 私钥不在 [1, n) 范围内时返回 `false`
+pub fn compute_public_key(
+    private_key: &[u8; PRIVATE_KEY_SIZE],
+    public_key: &mut [u8; PUBLIC_KEY_SIZE],
+) -> bool {
+    unsafe {
+        let curve = micro_uecc_sys::uECC_secp256k1();
+        micro_uecc_sys::uECC_compute_public_key(
+            private_key.as_ptr(),
+            public_key.as_mut_ptr(),
+            curve,
+        ) == 1
+    }
+}
@@ -0,0 +1,72 @@
+//! 纯 Rust 的 k256, 不需要 C 编译器及 libclang
+//!
+//! 密钥的字节格式与 micro-ecc 相同: 私钥 32 字节, 公钥 64 字节的 X || Y
+
+use k256::ecdh;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{PublicKey, SecretKey};
+use zeroize::Zeroize;
+
+use crate::key::{PRIVATE_KEY_SIZE, PUBLIC_KEY_SIZE, SHARED_SECRET_SIZE};
+
+fn secret_key(private_key: &[u8; PRIVATE_KEY_SIZE]) -> Option<SecretKey> {
+    SecretKey::from_slice(private_key).ok()
+}
+
+fn public_key(public_key: &[u8; PUBLIC_KEY_SIZE]) -> Option<PublicKey> {
+    let mut sec1 = [0x04; PUBLIC_KEY_SIZE + 1];
+    sec1[1..].copy_from_slice(public_key);
+    PublicKey::from_sec1_bytes(&sec1).ok()
+}
+
+fn write_public_key(key: &PublicKey, public_key: &mut [u8; PUBLIC_KEY_SIZE]) {
+    // 去掉 SEC1 未压缩格式开头的 0x04
+    public_key.copy_from_slice(&key.to_encoded_point(false).as_bytes()[1..]);
+}
+
+/// 生成密钥对
+pub fn make_key(
+    private_key: &mut [u8; PRIVATE_KEY_SIZE],
+    public_key: &mut [u8; PUBLIC_KEY_SIZE],
+) -> bool {
+    let secret = SecretKey::random(&mut rand_core::OsRng);
+    let mut bytes = secret.to_bytes();
+    private_key.copy_from_slice(&bytes);
+    bytes[..].zeroize();
+    write_public_key(&secret.public_key(), public_key);
+    true
+}
+
+/// ECDH, 共享密钥为 X 坐标
+pub fn shared_secret(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    private_key: &[u8; PRIVATE_KEY_SIZE],
+    secret: &mut [u8; SHARED_SECRET_SIZE],
+) -> bool {
+    let (public_key, private_key) = match (self::public_key(public_key), secret_key(private_key)) {
+        (Some(public_key), Some(private_key)) => (public_key, private_key),
+        _ => return false,
+    };
+    let shared = ecdh::diffie_hellman(private_key.to_nonzero_scalar(), public_key.as_affine());
+    secret.copy_from_slice(shared.raw_secret_bytes());
+    true
+}
+
+/// 检查公钥是否为 secp256k1 曲线上的合法点
+pub fn valid_public_key(public_key: &[u8; PUBLIC_KEY_SIZE]) -> bool {
+    self::public_key(public_key).is_some()
+}
+
+/// 由私钥计算公钥, 私钥不在 [1, n) 范围内时返回 `false`
+pub fn compute_public_key(
+    private_key: &[u8; PRIVATE_KEY_SIZE],
+    public_key: &mut [u8; PUBLIC_KEY_SIZE],
+) -> bool {
+    match secret_key(private_key) {
+        Some(secret) => {
+            write_public_key(&secret.public_key(), public_key);
+            true
+        }
+        None => false,
+    }
+}
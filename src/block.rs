@@ -0,0 +1,638 @@
+use serde::Serialize;
+use std::fmt;
+use std::ops::Range;
+
+use crate::decode::read_integer;
+use crate::error::DecodeError;
+
+#[allow(dead_code)]
+pub(crate) mod magic {
+    pub const CRYPT_START: u8 = 0x01;
+    pub const COMPRESS_CRYPT_START: u8 = 0x02;
+    pub const NO_COMPRESS_START: u8 = 0x03;
+    pub const NO_COMPRESS_START1: u8 = 0x06;
+    pub const NO_COMPRESS_NO_CRYPT_START: u8 = 0x08;
+    pub const COMPRESS_START: u8 = 0x04;
+    pub const COMPRESS_START1: u8 = 0x05;
+    pub const COMPRESS_START2: u8 = 0x07;
+    pub const COMPRESS_NO_CRYPT_START: u8 = 0x09;
+
+    pub const SYNC_ZLIB_START: u8 = 0x06;
+    pub const SYNC_NO_CRYPT_ZLIB_START: u8 = 0x08;
+    pub const SYNC_ZSTD_START: u8 = 0x0A;
+    pub const SYNC_NO_CRYPT_ZSTD_START: u8 = 0x0B;
+    pub const ASYNC_ZSTD_START: u8 = 0x0C;
+    pub const ASYNC_NO_CRYPT_ZSTD_START: u8 = 0x0D;
+
+    pub const END: u8 = 0x00;
+}
+
+/// block 头部 magic 对应的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum MagicKind {
+    /// 旧版本未压缩格式
+    NoCompress = magic::NO_COMPRESS_START,
+    /// 旧版本 zlib 格式
+    Compress = magic::COMPRESS_START,
+    /// 旧版本 zlib 格式, 每条日志带 2 字节长度前缀
+    CompressSegmented = magic::COMPRESS_START1,
+    SyncZlib = magic::SYNC_ZLIB_START,
+    AsyncZlib = magic::COMPRESS_START2,
+    SyncNoCryptZlib = magic::SYNC_NO_CRYPT_ZLIB_START,
+    AsyncNoCryptZlib = magic::COMPRESS_NO_CRYPT_START,
+    SyncZstd = magic::SYNC_ZSTD_START,
+    SyncNoCryptZstd = magic::SYNC_NO_CRYPT_ZSTD_START,
+    AsyncZstd = magic::ASYNC_ZSTD_START,
+    AsyncNoCryptZstd = magic::ASYNC_NO_CRYPT_ZSTD_START,
+}
+
+/// payload 实际使用的压缩方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    None,
+    Zlib,
+    Zstd,
+}
+
+impl MagicKind {
+    pub fn from_u8(value: u8) -> Option<MagicKind> {
+        let kind = match value {
+            magic::NO_COMPRESS_START => MagicKind::NoCompress,
+            magic::COMPRESS_START => MagicKind::Compress,
+            magic::COMPRESS_START1 => MagicKind::CompressSegmented,
+            magic::SYNC_ZLIB_START => MagicKind::SyncZlib,
+            magic::COMPRESS_START2 => MagicKind::AsyncZlib,
+            magic::SYNC_NO_CRYPT_ZLIB_START => MagicKind::SyncNoCryptZlib,
+            magic::COMPRESS_NO_CRYPT_START => MagicKind::AsyncNoCryptZlib,
+            magic::SYNC_ZSTD_START => MagicKind::SyncZstd,
+            magic::SYNC_NO_CRYPT_ZSTD_START => MagicKind::SyncNoCryptZstd,
+            magic::ASYNC_ZSTD_START => MagicKind::AsyncZstd,
+            magic::ASYNC_NO_CRYPT_ZSTD_START => MagicKind::AsyncNoCryptZstd,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    pub fn value(self) -> u8 {
+        self as u8
+    }
+
+    /// 头部中 client 公钥字段的长度
+    pub fn crypt_key_len(self) -> usize {
+        match self {
+            MagicKind::NoCompress | MagicKind::Compress | MagicKind::CompressSegmented => 4,
+            _ => 64,
+        }
+    }
+
+    /// 完整头部长度: magic + seq + begin_hour + end_hour + length + client 公钥
+    pub fn header_len(self) -> usize {
+        1 + 2 + 1 + 1 + 4 + self.crypt_key_len()
+    }
+
+    /// 同步模式写入的 block, payload 不压缩也不加密
+    pub fn is_sync(self) -> bool {
+        matches!(
+            self,
+            MagicKind::SyncZlib
+                | MagicKind::SyncNoCryptZlib
+                | MagicKind::SyncZstd
+                | MagicKind::SyncNoCryptZstd
+        )
+    }
+
+    /// magic 是否属于加密变体, 同步模式下即使是加密变体 payload 也是明文
+    pub fn is_crypt_variant(self) -> bool {
+        matches!(
+            self,
+            MagicKind::SyncZlib | MagicKind::AsyncZlib | MagicKind::SyncZstd | MagicKind::AsyncZstd
+        )
+    }
+
+    /// payload 是否经过 TEA 加密
+    pub fn is_crypt(self) -> bool {
+        matches!(self, MagicKind::AsyncZlib | MagicKind::AsyncZstd)
+    }
+
+    pub fn compression(self) -> Compression {
+        match self {
+            MagicKind::Compress
+            | MagicKind::CompressSegmented
+            | MagicKind::AsyncZlib
+            | MagicKind::AsyncNoCryptZlib => Compression::Zlib,
+            MagicKind::AsyncZstd | MagicKind::AsyncNoCryptZstd => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MagicKind::NoCompress => "legacy-no-compress",
+            MagicKind::Compress => "legacy-zlib",
+            MagicKind::CompressSegmented => "legacy-zlib-segmented",
+            MagicKind::SyncZlib => "sync-zlib-crypt",
+            MagicKind::AsyncZlib => "async-zlib-crypt",
+            MagicKind::SyncNoCryptZlib => "sync-zlib-no-crypt",
+            MagicKind::AsyncNoCryptZlib => "async-zlib-no-crypt",
+            MagicKind::SyncZstd => "sync-zstd-crypt",
+            MagicKind::SyncNoCryptZstd => "sync-zstd-no-crypt",
+            MagicKind::AsyncZstd => "async-zstd-crypt",
+            MagicKind::AsyncNoCryptZstd => "async-zstd-no-crypt",
+        }
+    }
+}
+
+impl fmt::Display for MagicKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// xlog 文件中的一个 block
+///
+/// 布局: magic(1) | seq(2) | begin_hour(1) | end_hour(1) | length(4) | client 公钥(4 或 64) | payload(length) | END(1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XlogBlock {
+    /// block 在输入中的起始位置
+    pub offset: usize,
+    /// 定位到本 block 之前因数据损坏跳过的字节数
+    pub skipped: usize,
+    pub magic: MagicKind,
+    pub compression: Compression,
+    /// payload 是否经过 TEA 加密
+    pub crypt: bool,
+    pub seq: u16,
+    pub begin_hour: u8,
+    pub end_hour: u8,
+    /// payload 在输入中的范围
+    pub payload: Range<usize>,
+    /// 加密变体 block 头部携带的 client 公钥
+    pub client_pubkey: Option<[u8; 64]>,
+}
+
+impl XlogBlock {
+    /// 解析 `offset` 处的 block 头部, 不校验 payload 及 END
+    pub fn parse_header(buf: &[u8], offset: usize) -> Option<XlogBlock> {
+        let magic = MagicKind::from_u8(*buf.get(offset)?)?;
+        let header_len = magic.header_len();
+        let header = buf.get(offset..offset + header_len)?;
+
+        let seq = read_integer::<u16>(&header[1..3]);
+        let length = read_integer::<u32>(&header[5..9]) as usize;
+
+        let mut client_pubkey = None;
+        if magic.crypt_key_len() == 64 && magic.is_crypt_variant() {
+            let mut key = [0; 64];
+            key.copy_from_slice(&header[9..]);
+            client_pubkey = Some(key);
+        }
+
+        let payload_start = offset + header_len;
+        Some(XlogBlock {
+            offset,
+            skipped: 0,
+            magic,
+            compression: magic.compression(),
+            crypt: magic.is_crypt(),
+            seq,
+            begin_hour: header[3],
+            end_hour: header[4],
+            payload: payload_start..payload_start + length,
+            client_pubkey,
+        })
+    }
+
+    pub fn header_len(&self) -> usize {
+        self.magic.header_len()
+    }
+
+    /// 包含 END 在内的 block 结束位置, 即下一个 block 的起始位置
+    pub fn end(&self) -> usize {
+        self.payload.end + 1
+    }
+
+    pub fn payload<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.payload.clone()]
+    }
+
+    /// block 的小时范围 `begin_hour..=end_hour` 是否与 `begin..=end` 有重叠
+    ///
+    /// 两个范围都可以跨过零点, 例如 `23..=1`; 头部的小时不合法时认为有重叠
+    pub fn overlaps_hours(&self, begin: u8, end: u8) -> bool {
+        hour_mask(self.begin_hour, self.end_hour) & hour_mask(begin, end) != 0
+    }
+}
+
+/// 相邻 block 之间不连续的 seq, `expected..seen` 范围内的 block 缺失
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SeqGap {
+    /// 上一个 block 的 seq + 1
+    pub expected: u16,
+    /// 缺失之后第一个 block 的 seq
+    pub seen: u16,
+    /// 缺失之后第一个 block 的起始位置
+    pub offset: usize,
+    pub begin_hour: u8,
+    pub end_hour: u8,
+}
+
+impl SeqGap {
+    /// 缺失的 block 数量
+    ///
+    /// seq 变小通常是另一个进程写入的 block (例如多个文件拼接), 无法得知缺失数量, 返回 0
+    pub fn missing(&self) -> u16 {
+        self.seen.saturating_sub(self.expected)
+    }
+
+    /// 缺失的最后一个 seq
+    pub fn last(&self) -> u16 {
+        self.seen.wrapping_sub(1)
+    }
+}
+
+/// 检查 `block` 之前是否有缺失的 seq, 并更新 `last_seq`
+///
+/// 与 python 脚本一致, seq 为 0 (同步模式) 或 1 (重新启动) 时不算缺失
+pub(crate) fn seq_gap(last_seq: &mut u16, block: &XlogBlock) -> Option<SeqGap> {
+    let seq = block.seq;
+    let mut gap = None;
+    if seq != 0 && seq != 1 && *last_seq != 0 && seq != last_seq.wrapping_add(1) {
+        gap = Some(SeqGap {
+            expected: last_seq.wrapping_add(1),
+            seen: seq,
+            offset: block.offset,
+            begin_hour: block.begin_hour,
+            end_hour: block.end_hour,
+        });
+    }
+    if seq != 0 {
+        *last_seq = seq;
+    }
+    gap
+}
+
+/// 按 block 遍历 xlog 数据, 遇到损坏的数据时与解码逻辑一致向后查找下一个合法 block
+pub struct BlockIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    started: bool,
+    done: bool,
+    stop_at_truncated: bool,
+    mmap_buffer: bool,
+    /// 长度损坏的 block 之后重新同步时跳过的字节数
+    resynced: usize,
+}
+
+impl<'a> BlockIter<'a> {
+    pub fn new(buf: &'a [u8]) -> BlockIter<'a> {
+        BlockIter {
+            buf,
+            offset: 0,
+            started: false,
+            done: false,
+            stop_at_truncated: false,
+            mmap_buffer: false,
+            resynced: 0,
+        }
+    }
+
+    /// 按 mars 异步模式的 mmap 缓存 (`.mmap3`) 解析, 同时启用 `stop_at_truncated`
+    ///
+    /// 缓存中正在写入的 block 还没有 END, 之后的空间都是 0, 末尾可能还有写了一半的数据,
+    /// 因此头部合法且数据没有超出缓存时不检查 END, 剩余数据全为 0 时结束
+    pub fn mmap_buffer(mut self) -> BlockIter<'a> {
+        self.mmap_buffer = true;
+        self.stop_at_truncated = true;
+        self
+    }
+
+    /// mmap 缓存中 `offset` 处数据完整但可能没有 END 的 block
+    fn unflushed_at(&self, offset: usize) -> Option<XlogBlock> {
+        let block = XlogBlock::parse_header(self.buf, offset)?;
+        if block.payload.end > self.buf.len() {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// 当前位置的 block 头部完整但数据在末尾被截断时直接返回 `Truncated`, 不再向后查找
+    ///
+    /// 头部中的 0 很容易被误认为合法的 block, 向后查找通常只会找到截断 block 内部的假 block;
+    /// 之后还能找到连续的合法 block 时说明是中间 block 的长度损坏, 返回错误后从该 block 继续
+    pub fn stop_at_truncated(mut self) -> BlockIter<'a> {
+        self.stop_at_truncated = true;
+        self
+    }
+
+    fn is_truncated_at(&self, offset: usize) -> bool {
+        match XlogBlock::parse_header(self.buf, offset) {
+            Some(block) => self.stop_at_truncated && block.end() > self.buf.len(),
+            None => false,
+        }
+    }
+
+    /// 下一个 block 的查找起点
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// 是否已经结束, 返回错误后仍未结束说明之后还有 block
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// 当前位置的 block 被截断时返回 `tail_error`, 之后没有合法 block 时结束
+    fn truncated_error(&mut self) -> Option<DecodeError> {
+        if !self.is_truncated_at(self.offset) {
+            return None;
+        }
+        let error = self.tail_error();
+        // 之后的 block 不会与当前 block 的头部重叠, 并要求连续两个合法 block,
+        // 避免把截断 block 内部的数据当作 block
+        let header_end = XlogBlock::parse_header(self.buf, self.offset)?.payload.start;
+        match get_log_start_pos(&self.buf[header_end..], 2) {
+            Some(pos) => {
+                self.resynced = header_end + pos - self.offset;
+                self.offset = header_end + pos;
+            }
+            None => self.done = true,
+        }
+        Some(error)
+    }
+
+    /// 之后再无合法 block 时的错误: 当前位置是被截断的 block 时返回 `Truncated`
+    fn tail_error(&self) -> DecodeError {
+        let offset = self.offset;
+        let remaining = self.buf.len() - offset;
+        let magic = match MagicKind::from_u8(self.buf[offset]) {
+            Some(it) => it,
+            None => return DecodeError::NoValidBlock { offset, remaining },
+        };
+        match XlogBlock::parse_header(self.buf, offset) {
+            Some(block) if block.end() > self.buf.len() => {
+                DecodeError::truncated(&block, block.end() - offset, remaining)
+            }
+            Some(_) => DecodeError::NoValidBlock { offset, remaining },
+            None => DecodeError::Truncated {
+                offset,
+                seq: self.buf.get(offset + 1..offset + 3).map(read_integer::<u16>),
+                magic,
+                needed: magic.header_len() + 1,
+                available: remaining,
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Result<XlogBlock, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut skipped = std::mem::take(&mut self.resynced);
+        if self.mmap_buffer {
+            let rest = self.buf.get(self.offset..).unwrap_or_default();
+            if rest.iter().all(|&it| it == 0) {
+                self.done = true;
+                return None;
+            }
+            if let Some(mut block) = self.unflushed_at(self.offset) {
+                block.skipped = skipped;
+                self.started = true;
+                self.offset = block.end();
+                // 没有 END 的是正在写入的 block, 之后只会是写了一半的数据
+                if self.buf.get(block.payload.end) != Some(&magic::END) {
+                    self.done = true;
+                }
+                return Some(Ok(block));
+            }
+        }
+
+        if !self.started {
+            self.started = true;
+            if let Some(error) = self.truncated_error() {
+                return Some(Err(error));
+            }
+            match get_log_start_pos(self.buf, 2) {
+                Some(pos) => {
+                    skipped = pos;
+                    self.offset = pos;
+                }
+                None => {
+                    self.done = true;
+                    return Some(Err(DecodeError::NotXlog {
+                        len: self.buf.len(),
+                    }));
+                }
+            }
+        }
+
+        if self.offset >= self.buf.len() {
+            self.done = true;
+            return None;
+        }
+
+        if !is_good_log_buf(self.buf, self.offset, 1) {
+            if let Some(error) = self.truncated_error() {
+                return Some(Err(error));
+            }
+            match get_log_start_pos(&self.buf[self.offset..], 1) {
+                Some(fixpos) => {
+                    skipped = fixpos;
+                    self.offset += fixpos;
+                }
+                None => {
+                    self.done = true;
+                    return Some(Err(self.tail_error()));
+                }
+            }
+        }
+
+        // is_good_log_buf 已经校验过头部及 END
+        let mut block = XlogBlock::parse_header(self.buf, self.offset)?;
+        block.skipped = skipped;
+        self.offset = block.end();
+        Some(Ok(block))
+    }
+}
+
+/// 遍历 `buf` 中的所有 block
+pub fn blocks(buf: &[u8]) -> BlockIter<'_> {
+    BlockIter::new(buf)
+}
+
+pub(crate) fn is_good_log_buf(buf: &[u8], offset: usize, count: i8) -> bool {
+    if offset == buf.len() {
+        return true;
+    }
+    let magic_value = match MagicKind::from_u8(buf[offset]) {
+        Some(it) => it,
+        None => return false,
+    };
+
+    let crypt_key_len = magic_value.crypt_key_len();
+    let header_len = magic_value.header_len();
+    if (offset + header_len + 1 + 1) > buf.len() {
+        return false;
+    }
+
+    let start = offset + header_len - crypt_key_len - 4;
+    let end = start + 4;
+    let bytes = &buf[start..end];
+    let length = read_integer::<u32>(bytes) as usize;
+    if (offset + header_len + length + 1) > buf.len() {
+        return false;
+    }
+    if magic::END != buf[offset + header_len + length] {
+        return false;
+    }
+    if count >= 1 {
+        return true;
+    }
+    is_good_log_buf(buf, offset + header_len + length + 1, count - 1)
+}
+
+pub(crate) fn get_log_start_pos(buf: &[u8], count: i8) -> Option<usize> {
+    let mut offset: usize = 0;
+    loop {
+        if offset >= buf.len() {
+            break;
+        }
+        if MagicKind::from_u8(buf[offset]).is_some() && is_good_log_buf(buf, offset, count) {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+/// 小时范围对应的位图, 第 n 位表示 n 点
+fn hour_mask(begin: u8, end: u8) -> u32 {
+    if begin > 23 || end > 23 {
+        return (1 << 24) - 1;
+    }
+    let mut mask = 0;
+    let mut hour = begin;
+    loop {
+        mask |= 1 << hour;
+        if hour == end {
+            return mask;
+        }
+        hour = (hour + 1) % 24;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::sample_data_path;
+
+    #[test]
+    fn blocks_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zlib_async_no_crypt_20220110.xlog")).unwrap();
+
+        let blocks: Vec<XlogBlock> = blocks(&input).map(|it| it.unwrap()).collect();
+        assert!(!blocks.is_empty());
+
+        let first = &blocks[0];
+        assert_eq!(first.offset, 0);
+        assert_eq!(first.magic, MagicKind::AsyncNoCryptZlib);
+        assert_eq!(first.compression, Compression::Zlib);
+        assert!(!first.crypt);
+        assert_eq!(first.client_pubkey, None);
+        assert_eq!(first.seq, 5);
+        assert_eq!(first.begin_hour, 15);
+        assert_eq!(first.end_hour, 15);
+        assert_eq!(first.payload, 73..73 + 246);
+
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[0].end(), pair[1].offset);
+        }
+        assert_eq!(blocks.last().unwrap().end(), input.len());
+    }
+
+    #[test]
+    fn blocks_resync_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zstd_async_crypt_20220110.xlog")).unwrap();
+        let expected: Vec<XlogBlock> = blocks(&input).map(|it| it.unwrap()).collect();
+        assert!(expected[0].client_pubkey.is_some());
+
+        // 在第一个 block 后插入垃圾数据
+        let mut corrupted = input[..expected[0].end()].to_vec();
+        corrupted.extend_from_slice(&[0xff; 7]);
+        corrupted.extend_from_slice(&input[expected[0].end()..]);
+
+        let blocks: Vec<XlogBlock> = blocks(&corrupted).map(|it| it.unwrap()).collect();
+        assert_eq!(blocks.len(), expected.len());
+        assert_eq!(blocks[1].skipped, 7);
+        assert_eq!(blocks[1].seq, expected[1].seq);
+    }
+
+    #[test]
+    fn blocks_error_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zlib_sync_no_crypt_20220110.xlog")).unwrap();
+
+        match blocks(&[0xff; 128]).next() {
+            Some(Err(DecodeError::NotXlog { len: 128 })) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let second = blocks(&input).nth(1).unwrap().unwrap();
+        let truncated = &input[..second.end() - 10];
+        match blocks(truncated).last() {
+            Some(Err(DecodeError::Truncated {
+                offset,
+                seq,
+                magic,
+                needed,
+                available,
+            })) => {
+                assert_eq!(offset, second.offset);
+                assert_eq!(seq, Some(second.seq));
+                assert_eq!(magic, second.magic);
+                assert_eq!(needed - available, 10);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let mut garbage = input.clone();
+        garbage.extend_from_slice(&[0xff; 16]);
+        match blocks(&garbage).last() {
+            Some(Err(DecodeError::NoValidBlock { offset, remaining: 16 })) => {
+                assert_eq!(offset, input.len())
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn overlaps_hours_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zlib_async_no_crypt_20220110.xlog")).unwrap();
+        let mut block = blocks(&input).next().unwrap().unwrap();
+
+        block.begin_hour = 10;
+        block.end_hour = 12;
+        assert!(block.overlaps_hours(12, 13));
+        assert!(block.overlaps_hours(8, 10));
+        assert!(!block.overlaps_hours(13, 9));
+        assert!(block.overlaps_hours(22, 11));
+
+        block.begin_hour = 23;
+        block.end_hour = 1;
+        assert!(block.overlaps_hours(0, 0));
+        assert!(block.overlaps_hours(22, 23));
+        assert!(!block.overlaps_hours(2, 22));
+
+        block.begin_hour = 0xff;
+        assert!(block.overlaps_hours(5, 5));
+    }
+}
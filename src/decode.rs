@@ -1,58 +1,26 @@
 use flate2::bufread;
+use flate2::{Decompress, FlushDecompress, Status};
 use memmap::Mmap;
+use micro_uecc_safe::PublicKey;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::prelude::*;
+use std::io::BufWriter;
 use std::io::Write;
+use std::sync::Mutex;
 
-mod utils {
-    use std::{fmt::Write, num::ParseIntError};
-
-    pub fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
-        (0..s.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-            .collect()
-    }
-
-    pub fn encode_hex(bytes: &[u8]) -> String {
-        let mut s = String::with_capacity(bytes.len() * 2);
-        for &b in bytes {
-            write!(&mut s, "{:02x}", b).unwrap();
-        }
-        s
-    }
-
-    pub fn tea_decrypt(v: &mut [u32], k: &mut [u32]) {
-        let mut v0 = v[0];
-        let mut v1 = v[1];
-        let delta: u32 = 0x9e3779b9;
-        let total_sum: u32 = 0x9e3779b9 << 4;
-
-        let mut sum: u32 = total_sum;
-        let k0 = k[0];
-        let k1 = k[1];
-        let k2 = k[2];
-        let k3 = k[3];
-        for _ in 0..16 {
-            let t1 = ((v0 << 4).wrapping_add(k2))
-                ^ (v0.wrapping_add(sum))
-                ^ ((v0 >> 5).wrapping_add(k3));
-            v1 = v1.wrapping_sub(t1);
-
-            let t0 = ((v1 << 4).wrapping_add(k0))
-                ^ (v1.wrapping_add(sum))
-                ^ ((v1 >> 5).wrapping_add(k1));
-            v0 = v0.wrapping_sub(t0);
-
-            sum = sum.wrapping_sub(delta);
-        }
-        v[0] = v0;
-        v[1] = v1;
-    }
-}
+use crate::block::{self, BlockIter, Compression, MagicKind, SeqGap, XlogBlock};
+use crate::error::DecodeError;
+use crate::filter::RecordFilter;
+use crate::keyring::{KeyUsage, Keyring, NamedKey};
+use crate::output::{self, OutputFormat};
+use crate::stream::{self, CopyError, SegmentReader, TeaReader};
+use crate::utils;
 
 pub trait ReadInteger<T> {
     fn from_le_bytes(data: &[u8]) -> T;
@@ -74,598 +42,1382 @@ macro_rules! impl_read_integer {
 
 impl_read_integer!(u8, i16, u16, i32, u32, i64);
 
-fn read_integer<T: ReadInteger<T>>(data: &[u8]) -> T {
+pub(crate) fn read_integer<T: ReadInteger<T>>(data: &[u8]) -> T {
     T::from_le_bytes(&data[..std::mem::size_of::<T>()])
 }
 
-mod magic {
-    pub const CRYPT_START: u8 = 0x01;
-    pub const COMPRESS_CRYPT_START: u8 = 0x02;
-    pub const NO_COMPRESS_START: u8 = 0x03;
-    pub const NO_COMPRESS_START1: u8 = 0x06;
-    pub const NO_COMPRESS_NO_CRYPT_START: u8 = 0x08;
-    pub const COMPRESS_START: u8 = 0x04;
-    pub const COMPRESS_START1: u8 = 0x05;
-    pub const COMPRESS_START2: u8 = 0x07;
-    pub const COMPRESS_NO_CRYPT_START: u8 = 0x09;
-
-    pub const SYNC_ZLIB_START: u8 = 0x06;
-    pub const SYNC_NO_CRYPT_ZLIB_START: u8 = 0x08;
-    pub const SYNC_ZSTD_START: u8 = 0x0A;
-    pub const SYNC_NO_CRYPT_ZSTD_START: u8 = 0x0B;
-    pub const ASYNC_ZSTD_START: u8 = 0x0C;
-    pub const ASYNC_NO_CRYPT_ZSTD_START: u8 = 0x0D;
-
-    pub const END: u8 = 0x00;
-}
-
-const BASE_KEY: u8 = 0xcc;
-const TEA_BLOCK_LEN: u8 = 8;
+/// 表示标准输入或标准输出的路径
+pub const STDIO_PATH: &str = "-";
 
+/// 基于文件路径的解码入口, 输入文件通过 mmap 读取
 pub struct Context {
     input: String,
     output: String,
-    private_key: String,
-    last_seq: i16,
+    decoder: Decoder,
+    format: OutputFormat,
+    filter: RecordFilter,
 }
 
-struct InputBuffer {
-    mmap: Mmap,
-    file_len: usize,
-    pos: usize,
+/// 内存解码器, 输入为完整的 xlog 数据, 输出写入任意 `Write`
+pub struct Decoder {
+    keyring: Keyring,
+    options: DecodeOptions,
+    last_seq: u16,
+    report: DecodeReport,
+    tea_keys: Mutex<TeaKeys>,
 }
 
-impl InputBuffer {
-    fn new(path: &str) -> anyhow::Result<InputBuffer> {
-        let in_file = File::open(path)?;
-        let in_file_len = in_file.metadata()?.len() as usize;
-        let in_mmap = unsafe { Mmap::map(&in_file)? };
-
-        let buf = InputBuffer {
-            mmap: in_mmap,
-            file_len: in_file_len,
-            pos: 0,
-        };
+/// client 公钥 -> (私钥在 `keyring` 中的位置, TEA 密钥)
+type TeaKeys = HashMap<[u8; 64], (usize, [u32; 4])>;
+
+/// 与 python 脚本一致的 seq 缺失提示
+pub const DEFAULT_GAP_MARKER: &str = "[F]decode_log_file.py log seq:{first}-{last} is missing";
+
+/// 解码选项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// 只解码小时范围与 `begin..=end` 有重叠的 block, 可以跨过零点, 例如 `(22, 2)`
+    ///
+    /// 其余 block 只读取头部, 不解密也不解压
+    pub hours: Option<(u8, u8)>,
+    /// 在线程池中并行解密解压 block, 输出顺序不变
+    pub parallel: bool,
+    /// seq 缺失时在纯文本输出中插入的一行, 为 `None` 时不插入
+    ///
+    /// 支持 `{first}`, `{last}`, `{missing}`, `{offset}` 占位符, 默认为 `DEFAULT_GAP_MARKER`
+    pub gap_marker: Option<String>,
+    /// block 解密解压失败时记录到 `DecodeReport::errors` 并继续解码之后的 block,
+    /// 末尾被截断的 block 尽可能解出出错之前的内容
+    pub recover: bool,
+    /// 输入是 mars 异步模式的 mmap 缓存 (`.mmap3`), 见 `BlockIter::mmap_buffer`
+    ///
+    /// 末尾写了一半的 block 总是尽可能解码, 不作为错误返回
+    pub mmap_buffer: bool,
+}
 
-        return Ok(buf);
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            hours: None,
+            parallel: false,
+            gap_marker: Some(String::from(DEFAULT_GAP_MARKER)),
+            recover: false,
+            mmap_buffer: false,
+        }
     }
+}
 
-    fn len(&self) -> usize {
-        self.file_len
+impl DecodeOptions {
+    fn gap_marker(&self, gap: &SeqGap) -> Option<String> {
+        let marker = self.gap_marker.as_ref()?;
+        Some(
+            marker
+                .replace("{first}", &gap.expected.to_string())
+                .replace("{last}", &gap.last().to_string())
+                .replace("{missing}", &gap.missing().to_string())
+                .replace("{offset}", &gap.offset.to_string()),
+        )
     }
 
-    fn cur_pos(&self) -> usize {
-        self.pos
+    fn skip(&self, block: &XlogBlock) -> bool {
+        match self.hours {
+            Some((begin, end)) => !block.overlaps_hours(begin, end),
+            None => false,
+        }
     }
+}
+
+/// 一次解码的统计信息
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DecodeReport {
+    /// 找到的合法 block 数量, 包括按小时范围跳过的 block
+    pub blocks: usize,
+    pub seq_gaps: Vec<SeqGap>,
+    /// 加密 block 的 client 公钥及解密使用的私钥
+    pub keys: Vec<KeyUsage>,
+    /// recover 模式下跳过的错误
+    pub errors: Vec<RecoveredError>,
+}
+
+/// recover 模式下跳过的一个错误
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecoveredError {
+    /// 出错 block 的起始位置
+    pub offset: Option<usize>,
+    pub seq: Option<u16>,
+    pub message: String,
+}
 
-    fn all_bytes(&self) -> &[u8] {
-        &self.mmap[0..self.file_len]
+impl RecoveredError {
+    fn new(error: &DecodeError) -> RecoveredError {
+        RecoveredError {
+            offset: error.offset(),
+            seq: error.seq(),
+            message: error.to_string(),
+        }
     }
+}
 
-    fn bytes(&self) -> &[u8] {
-        &self.mmap[self.pos..]
+impl DecodeReport {
+    /// 所有缺失的 block 数量, 可以与 `blocks` 一起计算日志丢失率
+    pub fn missing_blocks(&self) -> u64 {
+        self.seq_gaps.iter().map(|it| it.missing() as u64).sum()
     }
+}
 
-    fn seek(&mut self, pos: usize) {
-        self.pos = pos
+struct OutputBuffer<'w, W: Write> {
+    writer: &'w mut W,
+}
+
+impl<'w, W: Write> OutputBuffer<'w, W> {
+    fn new(writer: &'w mut W) -> OutputBuffer<'w, W> {
+        OutputBuffer { writer }
     }
 
-    fn bytes_at(&self, pos: usize) -> &[u8] {
-        &self.mmap[pos..]
+    fn appen_str(&mut self, str: &str) -> Result<(), io::Error> {
+        self.appen_bytes(str.as_bytes())
     }
 
-    fn range_bytes(&self, pos: usize, len: usize) -> &[u8] {
-        if (pos + len) > self.file_len {
-            panic!("越界");
+    fn appen_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        if bytes.is_empty() {
+            return Ok(());
         }
-        &self.mmap[pos..pos + len]
+        self.writer.write_all(bytes)
     }
-}
 
-struct OutputBufFile {
-    file: File,
-    write_pos: usize,
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
 }
 
-impl OutputBufFile {
-    fn new(path: &str) -> anyhow::Result<OutputBufFile> {
-        let out_file = OpenOptions::new()
-            .write(true)
-            // .read(true)
-            // .append(true)
-            .truncate(true)
-            .create(true)
-            .open(&path)?;
+impl Decoder {
+    pub fn new(private_key: &str) -> Decoder {
+        Decoder::with_options(private_key, DecodeOptions::default())
+    }
 
-        // out_file.write_all([0x0]);
+    pub fn with_options(private_key: &str, options: DecodeOptions) -> Decoder {
+        Decoder::with_keyring(Keyring::single(private_key), options)
+    }
 
-        let buf = OutputBufFile {
-            file: out_file,
-            write_pos: 0,
-        };
+    /// 使用多个私钥解码, 每个 client 公钥使用第一个能解出合法压缩数据的私钥
+    pub fn with_keyring(keyring: Keyring, options: DecodeOptions) -> Decoder {
+        Decoder {
+            keyring,
+            options,
+            last_seq: 0,
+            report: DecodeReport::default(),
+            tea_keys: Mutex::new(HashMap::new()),
+        }
+    }
 
-        return Ok(buf);
+    pub fn set_options(&mut self, options: DecodeOptions) {
+        self.options = options;
     }
 
-    fn appen_str(&mut self, str: &str) -> Result<(), io::Error> {
-        if str.len() == 0 {
-            return Ok(());
-        }
-        let bytes = str.as_bytes();
-        if let Err(e) = self.file.write_all(bytes) {
-            return Err(e);
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.keyring = keyring;
+        self.tea_keys.lock().unwrap().clear();
+    }
+
+    /// 最近一次 `decode` 或 `for_each_block` 的统计信息
+    pub fn report(&self) -> &DecodeReport {
+        &self.report
+    }
+
+    /// recover 模式下遇到截断的 block 时停止查找, 以便解出其中的内容
+    fn blocks<'a>(&self, input: &'a [u8]) -> BlockIter<'a> {
+        let iter = BlockIter::new(input);
+        if self.options.mmap_buffer {
+            iter.mmap_buffer()
+        } else if self.options.recover {
+            iter.stop_at_truncated()
         } else {
-            self.write_pos = bytes.len();
-            return Ok(());
+            iter
         }
     }
 
-    fn appen_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
-        if bytes.len() == 0 {
-            return Ok(());
-        }
-        if let Err(e) = self.file.write_all(bytes) {
-            return Err(e);
+    fn reset(&mut self) {
+        self.last_seq = 0;
+        self.report = DecodeReport::default();
+    }
+
+    /// 解码完整的 xlog 数据并写入 `output`
+    ///
+    /// 非并行模式下直接解压到 `output`, 内存占用与 block 大小无关;
+    /// 并行模式下每个 block 先完整解压到内存中
+    pub fn decode<W: Write>(&mut self, input: &[u8], output: &mut W) -> Result<(), DecodeError> {
+        self.reset();
+        self.check_key()?;
+        let mut output_buf = OutputBuffer::new(output);
+        if self.options.parallel {
+            let mut index = 0;
+            self.visit_blocks(input, |this, block, decoded| {
+                this.write_markers(index, block, &mut output_buf)?;
+                index += 1;
+                if let Some(decoded) = decoded {
+                    output_buf.appen_bytes(decoded)?;
+                }
+                Ok(())
+            })?;
         } else {
-            self.write_pos = bytes.len();
-            return Ok(());
+            let mut iter = self.blocks(input);
+            let mut index = 0;
+            while let Some(block) = iter.next() {
+                let block = match block {
+                    Ok(it) => it,
+                    Err(e) if !iter.is_done() => {
+                        self.recover_resync(e)?;
+                        continue;
+                    }
+                    Err(e) => {
+                        if let Some(block) = self.recover_tail(input, e)? {
+                            self.write_markers(index, &block, &mut output_buf)?;
+                            if !self.options.skip(&block) {
+                                self.salvage_block(input, &block, &mut output_buf)?;
+                            }
+                        }
+                        break;
+                    }
+                };
+                self.write_markers(index, &block, &mut output_buf)?;
+                index += 1;
+                if !self.options.skip(&block) {
+                    let result = self.decode_block(input, &block, &mut output_buf);
+                    self.recover(result)?;
+                }
+            }
         }
+        self.resolve_report_keys();
+        output_buf.flush()?;
+        Ok(())
     }
 
-    fn flush(&mut self) {
-        self.file.flush();
+    /// 逐个 block 解码, 将 block 信息和解码后的内容交给 `f`
+    ///
+    /// 与 `decode` 不同, 不会输出 seq 缺失等提示信息, 缺失的 seq 只记录在 `report` 中,
+    /// 每个 block 的内容会完整解压到内存中
+    pub fn for_each_block<F>(&mut self, input: &[u8], mut f: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(&XlogBlock, &[u8]) -> Result<(), DecodeError>,
+    {
+        self.reset();
+        self.check_key()?;
+        self.visit_blocks(input, |this, block, decoded| {
+            this.check_seq(block);
+            match decoded {
+                Some(decoded) => f(block, decoded),
+                None => Ok(()),
+            }
+        })?;
+        self.resolve_report_keys();
+        Ok(())
     }
-}
 
-impl Context {
-    fn decode_buf(
-        &mut self,
-        input_buf_file: &mut InputBuffer,
-        offset: &mut usize,
-        output_buf_file: &mut OutputBufFile,
-    ) -> anyhow::Result<usize> {
-        let in_buf_len = input_buf_file.len();
-        if *offset >= in_buf_len {
-            let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer");
-            return Err(anyhow::Error::new(eof));
-        }
-        let in_buf = input_buf_file.bytes();
-        if !is_good_log_buf(in_buf, *offset, 1) {
-            let bytes = input_buf_file.bytes_at(*offset);
-            if let Some(fixpos) = get_log_start_pos(bytes, 1) {
-                output_buf_file.appen_str(&format!(
-                    "[F]decode_log_file.py decode err|| len= {:?}\n",
-                    fixpos
-                ))?;
-                *offset += fixpos;
-            } else {
-                return Err(anyhow::anyhow!("无法获取 log start pos"));
+    /// 按顺序将每个 block 及解码后的内容交给 `f`, 被跳过的 block 内容为 `None`
+    ///
+    /// 并行模式下先找出一批 block 的边界, 在线程池中解密解压后再按原顺序交给 `f`
+    fn visit_blocks<F>(&mut self, input: &[u8], mut f: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(&mut Decoder, &XlogBlock, Option<&[u8]>) -> Result<(), DecodeError>,
+    {
+        let mut iter = self.blocks(input);
+        if !self.options.parallel {
+            let mut decoded = Vec::new();
+            while let Some(block) = iter.next() {
+                let block = match block {
+                    Ok(it) => it,
+                    Err(e) if !iter.is_done() => {
+                        self.recover_resync(e)?;
+                        continue;
+                    }
+                    Err(e) => return self.visit_tail(input, e, f),
+                };
+                if self.options.skip(&block) {
+                    f(self, &block, None)?;
+                    continue;
+                }
+                decoded.clear();
+                let result = self.decode_block(input, &block, &mut OutputBuffer::new(&mut decoded));
+                self.recover(result)?;
+                f(self, &block, Some(&decoded))?;
             }
+            return Ok(());
         }
 
-        let mut crypt_key_len: usize = 0;
-        let magic_value = input_buf_file.bytes_at(*offset)[0];
-        if magic::NO_COMPRESS_START == magic_value
-            || magic::COMPRESS_START == magic_value
-            || magic::COMPRESS_START1 == magic_value
-        {
-            crypt_key_len = 4
-        } else if magic::COMPRESS_START2 == magic_value
-            || magic::NO_COMPRESS_START1 == magic_value
-            || magic::NO_COMPRESS_NO_CRYPT_START == magic_value
-            || magic::COMPRESS_NO_CRYPT_START == magic_value
-            || magic::SYNC_ZSTD_START == magic_value
-            || magic::SYNC_NO_CRYPT_ZSTD_START == magic_value
-            || magic::ASYNC_ZSTD_START == magic_value
-            || magic::ASYNC_NO_CRYPT_ZSTD_START == magic_value
-        {
-            crypt_key_len = 64
-        } else {
-            output_buf_file.appen_str(&format!(
-                "in DecodeBuffer _buffer[{:?}]:{:?} != NUM_START\n",
-                *offset, magic_value
-            ))?;
-            return Err(anyhow::anyhow!("无法获取 log start pos"));
+        let batch_len = rayon::current_num_threads() * 4;
+        loop {
+            let mut batch = Vec::with_capacity(batch_len);
+            let mut tail_error = None;
+            for block in iter.by_ref().take(batch_len) {
+                match block {
+                    Ok(block) => batch.push(block),
+                    Err(e) => {
+                        tail_error = Some(e);
+                        break;
+                    }
+                }
+            }
+            if batch.is_empty() && tail_error.is_none() {
+                return Ok(());
+            }
+
+            let this = &*self;
+            let results: Vec<_> = batch
+                .par_iter()
+                .map(|block| {
+                    if this.options.skip(block) {
+                        return None;
+                    }
+                    let mut decoded = Vec::new();
+                    let result =
+                        this.decode_block(input, block, &mut OutputBuffer::new(&mut decoded));
+                    Some((decoded, result))
+                })
+                .collect();
+            for (block, decoded) in batch.iter().zip(results) {
+                match decoded {
+                    Some((decoded, result)) => {
+                        self.recover(result)?;
+                        f(self, block, Some(&decoded))?
+                    }
+                    None => f(self, block, None)?,
+                }
+            }
+
+            if let Some(e) = tail_error {
+                if !iter.is_done() {
+                    self.recover_resync(e)?;
+                    continue;
+                }
+                return self.visit_tail(input, e, f);
+            }
         }
+    }
 
-        let header_len = 1 + 2 + 1 + 1 + 4 + crypt_key_len;
-        let start = *offset + header_len - 4 - crypt_key_len;
-        let len_bytes = input_buf_file.bytes_at(start);
-        let length = read_integer::<u32>(len_bytes) as usize;
+    /// `visit_blocks` 遇到 `BlockIter` 的错误时, recover 模式下将截断 block 中能解出的内容交给 `f`
+    fn visit_tail<F>(
+        &mut self,
+        input: &[u8],
+        error: DecodeError,
+        mut f: F,
+    ) -> Result<(), DecodeError>
+    where
+        F: FnMut(&mut Decoder, &XlogBlock, Option<&[u8]>) -> Result<(), DecodeError>,
+    {
+        let block = match self.recover_tail(input, error)? {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        if self.options.skip(&block) {
+            return f(self, &block, None);
+        }
+        let mut decoded = Vec::new();
+        self.salvage_block(input, &block, &mut OutputBuffer::new(&mut decoded))?;
+        f(self, &block, Some(&decoded))
+    }
 
-        let pos = *offset + header_len - 4 - crypt_key_len - 2 - 2;
-        let sqe_bytes = input_buf_file.bytes_at(pos);
-        let seq = read_integer::<i16>(sqe_bytes);
+    /// recover 模式下记录 block 解码的错误, 写入失败等无法恢复的错误仍然返回
+    fn recover(&mut self, result: Result<(), DecodeError>) -> Result<(), DecodeError> {
+        match result {
+            Err(e) if self.options.recover && !is_fatal(&e) => {
+                self.report.errors.push(RecoveredError::new(&e));
+                Ok(())
+            }
+            result => result,
+        }
+    }
 
-        if seq != 0 && seq != 1 && self.last_seq != 0 && seq != (self.last_seq + 1) {
-            output_buf_file.appen_str(&format!(
-                "[F]decode_log_file.py log seq:{:?}-{:?} is missing\n",
-                self.last_seq + 1,
-                seq - 1
-            ))?;
+    /// `BlockIter` 返回错误后重新同步到了之后的 block, recover 模式下记录错误并继续
+    fn recover_resync(&mut self, error: DecodeError) -> Result<(), DecodeError> {
+        if !(self.options.recover || self.options.mmap_buffer) || is_fatal(&error) {
+            return Err(error);
         }
+        self.report.errors.push(RecoveredError::new(&error));
+        Ok(())
+    }
 
-        if seq != 0 {
-            self.last_seq = seq;
+    /// recover 模式下记录 `BlockIter` 的错误, 出错位置是被截断的 block 时返回该 block,
+    /// payload 截取到数据末尾
+    fn recover_tail(
+        &mut self,
+        input: &[u8],
+        error: DecodeError,
+    ) -> Result<Option<XlogBlock>, DecodeError> {
+        let salvage = self.options.recover
+            || self.options.mmap_buffer && matches!(error, DecodeError::Truncated { .. });
+        if !salvage || is_fatal(&error) {
+            return Err(error);
+        }
+        self.report.errors.push(RecoveredError::new(&error));
+        let mut block = match error {
+            DecodeError::Truncated { offset, .. } => match XlogBlock::parse_header(input, offset) {
+                Some(it) => it,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let mut available = input.len() - block.payload.start;
+        if block.crypt {
+            // 只有完整的 8 字节块可以解密, 被截断的最后一块不能当作明文
+            available -= available % 8;
         }
+        block.payload.end = block.payload.start + available;
+        Ok(Some(block))
+    }
 
-        let pos = *offset + header_len;
-        let data = input_buf_file.range_bytes(pos, length);
-        let mut content_buf: Vec<u8> = Vec::with_capacity(length);
-        content_buf.extend_from_slice(data);
+    /// 解码被截断的 block, 保留解压出错之前的内容
+    fn salvage_block<W: Write>(
+        &self,
+        input: &[u8],
+        block: &XlogBlock,
+        output_buf_file: &mut OutputBuffer<W>,
+    ) -> Result<(), DecodeError> {
+        match self.decode_block(input, block, output_buf_file) {
+            Err(e) if is_fatal(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
 
-        let input_buf = input_buf_file.all_bytes();
-        let magic_value = input_buf[*offset];
+/// 文件扩展名是否为 mars mmap 缓存的 `.mmap3` 或旧版本的 `.mmap2`
+pub fn is_mmap_buffer(path: &str) -> bool {
+    matches!(
+        std::path::Path::new(path)
+            .extension()
+            .and_then(|it| it.to_str()),
+        Some("mmap3") | Some("mmap2")
+    )
+}
 
-        let is_crypt = self.private_key.len() > 0;
-        if is_crypt
-            && (magic::SYNC_ZLIB_START == magic_value
-                || magic::SYNC_NO_CRYPT_ZLIB_START == magic_value
-                || magic::SYNC_ZSTD_START == magic_value
-                || magic::SYNC_NO_CRYPT_ZSTD_START == magic_value)
-        {
-            output_buf_file.appen_bytes(data)?;
-        } else if !is_crypt
-            && (magic::NO_COMPRESS_START1 == magic_value
-                || magic::COMPRESS_START2 == magic_value
-                || magic::SYNC_ZSTD_START == magic_value
-                || magic::ASYNC_ZSTD_START == magic_value)
-        {
-            output_buf_file.appen_str("use wrong decode script\n")?;
-        } else if is_crypt
-            && (magic::COMPRESS_START2 == magic_value || magic::ASYNC_ZSTD_START == magic_value)
-        {
-            // 解密
+/// recover 模式下也无法继续的错误
+fn is_fatal(error: &DecodeError) -> bool {
+    matches!(
+        error,
+        DecodeError::NotXlog { .. } | DecodeError::InvalidKey { .. } | DecodeError::Io(_)
+    )
+}
 
-            let mut client_pub_key: Vec<u8> = vec![0; crypt_key_len];
-            let pos = *offset + header_len - crypt_key_len;
-            let len = crypt_key_len;
-            let data = input_buf_file.range_bytes(pos, len);
-            client_pub_key.copy_from_slice(data);
+/// 解码内存中的 xlog 数据, 返回解码后的日志内容
+pub fn decode_bytes(input: &[u8], private_key: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut output = Vec::with_capacity(input.len() * 4);
+    Decoder::new(private_key).decode(input, &mut output)?;
+    Ok(output)
+}
 
-            let mut svr_priate_key: Vec<u8> = vec![0];
-            if let Ok(decode) = utils::decode_hex(&self.private_key) {
-                svr_priate_key = decode;
-            } else {
-                return Err(anyhow::anyhow!("Get ECDH key error"));
-            }
+/// 从任意 `Read` 读取 xlog 数据并解码写入 `output`
+///
+/// 解码过程中需要向后查找合法的 block, 因此输入会被完整读入内存
+pub fn decode_reader<R: Read, W: Write>(
+    mut input: R,
+    output: &mut W,
+    private_key: &str,
+) -> Result<(), DecodeError> {
+    let mut buf = Vec::new();
+    input.read_to_end(&mut buf)?;
+    Decoder::new(private_key).decode(&buf, output)
+}
 
-            let mut ecdh_buf = vec![0; 32];
-            if let None = micro_uecc_safe::ucc_shared_secret_whith_secp2561k1(
-                &mut client_pub_key,
-                &mut svr_priate_key,
-                &mut ecdh_buf,
-            ) {
-                return Err(anyhow::anyhow!("Get ECDH key error"));
-            }
+impl Decoder {
+    /// 解码单个 block 写入 `output`, 不输出任何提示信息
+    pub(crate) fn decode_block_into<W: Write>(
+        &self,
+        input: &[u8],
+        block: &XlogBlock,
+        output: &mut W,
+    ) -> Result<(), DecodeError> {
+        self.decode_block(input, block, &mut OutputBuffer::new(output))
+    }
 
-            let mut tea_key = vec![0; 4];
-            for i in 0..4 {
-                let start = i * 4;
-                let end = start + 4;
-                let bytes = &ecdh_buf[start..end];
-                let t1 = read_integer::<u32>(bytes);
-                tea_key[i] = t1;
+    /// 是否设置了私钥
+    pub(crate) fn has_key(&self) -> bool {
+        !self.keyring.is_empty()
+    }
+
+    /// 检查每个私钥是否为合法的 secp256k1 私钥
+    pub(crate) fn check_key(&self) -> Result<(), DecodeError> {
+        self.keyring.validate()
+    }
+
+    /// 填入已经找到的每个 client 公钥对应的私钥 id
+    pub(crate) fn resolve_keys(&self, usages: &mut [KeyUsage]) {
+        let tea_keys = self.tea_keys.lock().unwrap();
+        for (pubkey, (index, _)) in tea_keys.iter() {
+            let pubkey = utils::encode_hex(pubkey);
+            if let Some(usage) = usages.iter_mut().find(|it| it.client_pubkey == pubkey) {
+                usage.key_id = Some(self.keyring.keys()[*index].id.clone());
             }
+        }
+    }
 
-            let tea_block_len = TEA_BLOCK_LEN as usize;
-            let cnt = length / tea_block_len;
-            for i in 0..cnt {
-                let start = i * tea_block_len;
-                let end = start + tea_block_len;
-
-                let bytes = &content_buf[start..end - 4];
-                let t1 = read_integer::<u32>(bytes);
-                let bytes = &content_buf[start + 4..end];
-                let t2 = read_integer::<u32>(bytes);
-                let mut tmp = vec![t1, t2];
-
-                utils::tea_decrypt(&mut tmp, &mut tea_key);
-
-                for i in 0..2 {
-                    let x = tmp[i];
-                    let b1: u8 = ((x >> 24) & 0xff) as u8;
-                    let b2: u8 = ((x >> 16) & 0xff) as u8;
-                    let b3: u8 = ((x >> 8) & 0xff) as u8;
-                    let b4: u8 = (x & 0xff) as u8;
-                    content_buf[start + i * 4] = b4;
-                    content_buf[start + i * 4 + 1] = b3;
-                    content_buf[start + i * 4 + 2] = b2;
-                    content_buf[start + i * 4 + 3] = b1;
-                }
-                // output_buf_file.appen_str(&format!("0x{:08X} 0x{:08X}\n", tmp[0], tmp[1]));
-                // println!("0x{:08X} 0x{:08X}", tmp[0], tmp[1]);
+    fn resolve_report_keys(&mut self) {
+        let mut usages = std::mem::take(&mut self.report.keys);
+        self.resolve_keys(&mut usages);
+        self.report.keys = usages;
+    }
+
+    /// 与 python 脚本一致, 在 block 内容之前输出跳过的字节数及缺失的 seq
+    fn write_markers<W: Write>(
+        &mut self,
+        index: usize,
+        block: &XlogBlock,
+        output_buf_file: &mut OutputBuffer<W>,
+    ) -> Result<(), DecodeError> {
+        if index > 0 && block.skipped > 0 {
+            output_buf_file.appen_str(&format!(
+                "[F]decode_log_file.py decode err|| len= {:?}\n",
+                block.skipped
+            ))?;
+        }
+        // 跳过的 block 也需要更新 seq, 否则会被误报为缺失
+        let marker = self
+            .check_seq(block)
+            .and_then(|gap| self.options.gap_marker(&gap));
+        if let Some(marker) = marker {
+            output_buf_file.appen_str(&marker)?;
+            output_buf_file.appen_str("\n")?;
+        }
+        Ok(())
+    }
+
+    /// 统计 block 数量并记录缺失的 seq
+    fn check_seq(&mut self, block: &XlogBlock) -> Option<SeqGap> {
+        self.report.blocks += 1;
+        KeyUsage::count(&mut self.report.keys, block);
+        let gap = block::seq_gap(&mut self.last_seq, block)?;
+        self.report.seq_gaps.push(gap);
+        Some(gap)
+    }
+
+    fn decode_block<W: Write>(
+        &self,
+        input: &[u8],
+        block: &XlogBlock,
+        output_buf_file: &mut OutputBuffer<W>,
+    ) -> Result<(), DecodeError> {
+        let data = match input.get(block.payload.clone()) {
+            Some(it) => it,
+            None => {
+                return Err(DecodeError::truncated(
+                    block,
+                    block.end() - block.offset,
+                    input.len().saturating_sub(block.offset),
+                ))
             }
+        };
+        let magic_value = block.magic;
+        let is_crypt = self.has_key();
+        if is_crypt && magic_value.is_sync() {
+            output_buf_file.appen_bytes(data)?;
+        } else if !is_crypt
+            && (MagicKind::SyncZlib == magic_value
+                || MagicKind::AsyncZlib == magic_value
+                || MagicKind::SyncZstd == magic_value
+                || MagicKind::AsyncZstd == magic_value)
+        {
+            output_buf_file.appen_str("use wrong decode script\n")?;
+        } else if is_crypt && magic_value.is_crypt() {
+            // 解密
+            let tea_key = self.tea_key(data, block)?;
+            let reader = TeaReader::new(data, tea_key);
 
-            if magic::COMPRESS_START2 == magic_value {
+            if Compression::Zlib == block.compression {
                 // zlib
-                self.zlib_decompress(output_buf_file, &content_buf)?;
+                self.zlib_decompress(block, output_buf_file, reader)?;
             } else {
                 // zstd
-                self.zstd_decompress(output_buf_file, &content_buf)?;
+                self.zstd_decompress(block, output_buf_file, reader)?;
             }
-        } else if magic::ASYNC_NO_CRYPT_ZSTD_START == magic_value {
+        } else if MagicKind::AsyncNoCryptZstd == magic_value {
             // zstd
-            self.zstd_decompress(output_buf_file, &content_buf)?;
-        } else if magic::COMPRESS_START == magic_value
-            || magic::COMPRESS_NO_CRYPT_START == magic_value
-        {
+            self.zstd_decompress(block, output_buf_file, data)?;
+        } else if MagicKind::Compress == magic_value || MagicKind::AsyncNoCryptZlib == magic_value {
             // zlib
-            self.zlib_decompress(output_buf_file, &content_buf)?;
-        } else if magic::COMPRESS_START1 == magic_value {
-            let mut decompress_buf: Vec<u8> = Vec::with_capacity(1024);
-
-            let mut tmpbuffer = &content_buf[0..];
-            while tmpbuffer.len() > 0 {
-                let single_log_len = read_integer::<u16>(&tmpbuffer[0..2]) as usize;
-                decompress_buf.extend_from_slice(&tmpbuffer[2..single_log_len + 2]);
-                tmpbuffer = &tmpbuffer[single_log_len + 2..];
+            self.zlib_decompress(block, output_buf_file, data)?;
+        } else if MagicKind::CompressSegmented == magic_value {
+            if let Err(consumed) = stream::check_segments(data) {
+                return Err(DecodeError::truncated(
+                    block,
+                    block.header_len() + consumed + 2,
+                    block.header_len() + data.len(),
+                ));
             }
             // zlib
-            self.zlib_decompress(output_buf_file, &decompress_buf)?;
+            self.zlib_decompress(block, output_buf_file, SegmentReader::new(data))?;
         } else {
-            output_buf_file.appen_bytes(&content_buf)?;
+            output_buf_file.appen_bytes(data)?;
         }
 
-        // TODO: 暂时中断
-        return Ok(*offset + header_len + length + 1);
+        Ok(())
     }
 
-    fn zlib_decompress(
-        &self,
-        output_buf_file: &mut OutputBufFile,
-        content_buf: &[u8],
-    ) -> anyhow::Result<()> {
-        if content_buf.len() == 0 {
-            return Ok(());
+    /// 同一次启动的 client 公钥会被很多 block 复用, ECDH 的结果按公钥缓存
+    ///
+    /// 有多个私钥时依次用每个私钥试解 `data`, 使用第一个能解出合法压缩数据的私钥
+    fn tea_key(&self, data: &[u8], block: &XlogBlock) -> Result<[u32; 4], DecodeError> {
+        let client_pub_key = match block.client_pubkey {
+            Some(key) => key,
+            None => return Err(DecodeError::key_agreement(block)),
+        };
+        if let Some((_, tea_key)) = self.tea_keys.lock().unwrap().get(&client_pub_key) {
+            return Ok(*tea_key);
+        }
+        // micro-ecc 的 ECDH 不检查公钥, 不在曲线上的点也会算出结果, 构造 PublicKey 时检查
+        let public_key =
+            PublicKey::from_array(client_pub_key).map_err(|_| DecodeError::key_agreement(block))?;
+
+        let keys = self.keyring.keys();
+        if keys.len() == 1 {
+            let tea_key = key_agreement(&public_key, &keys[0]);
+            self.cache_tea_key(client_pub_key, 0, tea_key);
+            return Ok(tea_key);
+        }
+        for (index, key) in keys.iter().enumerate() {
+            let tea_key = key_agreement(&public_key, key);
+            if self.trial_decode(data, block, tea_key) {
+                self.cache_tea_key(client_pub_key, index, tea_key);
+                return Ok(tea_key);
+            }
         }
-        let mut gz = bufread::DeflateDecoder::new(content_buf);
-        let mut s = Vec::new();
-        // if let Err(err) = gz.read_to_end(&mut s) {
-        //     return Err(anyhow::Error::new(err));
-        // } else {
-        //     output_buf_file.appen_bytes(&s)?;
-        // };
-
-        match gz.read_to_end(&mut s) {
-            Ok(_) => {
-                output_buf_file.appen_bytes(&s)?;
+        Err(DecodeError::NoMatchingKey {
+            offset: block.offset,
+            seq: block.seq,
+            magic: block.magic,
+            keys: keys.len(),
+        })
+    }
+
+    fn cache_tea_key(&self, client_pub_key: [u8; 64], index: usize, tea_key: [u32; 4]) {
+        self.tea_keys
+            .lock()
+            .unwrap()
+            .insert(client_pub_key, (index, tea_key));
+    }
+
+    /// 用 `tea_key` 试解 block 的 payload, 确定私钥匹配时返回 `true`
+    ///
+    /// 错误的私钥解密出的是随机数据, zstd 帧头的 magic 几乎不可能匹配;
+    /// zlib 的随机数据有时也能解压出几个字节, 因此只有压缩流恰好在 payload 末尾结束或以 sync flush 结尾时才算匹配,
+    /// 被截断的 block 只能使用之前的 block 已经确定的私钥
+    fn trial_decode(&self, data: &[u8], block: &XlogBlock, tea_key: [u32; 4]) -> bool {
+        if Compression::Zstd == block.compression {
+            let mut sink = io::sink();
+            let reader = TeaReader::new(data, tea_key);
+            return !data.is_empty()
+                && self
+                    .zstd_decompress(block, &mut OutputBuffer::new(&mut sink), reader)
+                    .is_ok();
+        }
+
+        // 边解密边解压, 记录已解压输入的最后 4 个字节
+        let mut reader = TeaReader::new(data, tea_key);
+        let mut decompress = Decompress::new(false);
+        let mut output = vec![0; 64 * 1024];
+        let mut tail = [0; 4];
+        loop {
+            let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+            let input = match reader.fill_buf() {
+                Ok(it) => it,
+                Err(_) => return false,
+            };
+            let status = decompress.decompress(input, &mut output, FlushDecompress::None);
+            let consumed = (decompress.total_in() - total_in) as usize;
+            if consumed >= 4 {
+                tail.copy_from_slice(&input[consumed - 4..consumed]);
+            } else {
+                tail.rotate_left(consumed);
+                tail[4 - consumed..].copy_from_slice(&input[..consumed]);
             }
-            Err(err) => {
-                return Err(anyhow::Error::new(err));
+            reader.consume(consumed);
+            match status {
+                Ok(Status::StreamEnd) => return decompress.total_in() as usize == data.len(),
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+            if decompress.total_in() == total_in && decompress.total_out() == total_out {
+                break;
             }
         }
+        decompress.total_in() as usize == data.len() && tail == [0x00, 0x00, 0xff, 0xff]
+    }
 
-        Ok(())
+    fn zlib_decompress<W: Write, R: BufRead>(
+        &self,
+        block: &XlogBlock,
+        output_buf_file: &mut OutputBuffer<W>,
+        content: R,
+    ) -> Result<(), DecodeError> {
+        let mut gz = bufread::DeflateDecoder::new(content);
+        match stream::copy(&mut gz, output_buf_file.writer) {
+            Ok(_) => Ok(()),
+            // mars 异步模式以 Z_SYNC_FLUSH 写入, 压缩流没有结束标记, 与 python 脚本一致保留已解压的内容
+            Err(CopyError::Read(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(CopyError::Read(err)) => Err(DecodeError::decompress(block, err)),
+            Err(CopyError::Write(err)) => Err(DecodeError::Io(err)),
+        }
     }
 
-    fn zstd_decompress(
+    fn zstd_decompress<W: Write, R: BufRead>(
         &self,
-        output_buf_file: &mut OutputBufFile,
-        content_buf: &[u8],
-    ) -> anyhow::Result<()> {
-        if content_buf.len() == 0 {
+        block: &XlogBlock,
+        output_buf_file: &mut OutputBuffer<W>,
+        mut content: R,
+    ) -> Result<(), DecodeError> {
+        if content.fill_buf()?.is_empty() {
             return Ok(());
         }
-        match zstd::stream::decode_all(content_buf) {
-            Ok(decompress) => {
-                output_buf_file.appen_bytes(&decompress)?;
-            }
-            Err(e) => {
-                return Err(anyhow::Error::new(e));
+        let mut decoder = match zstd::stream::read::Decoder::with_buffer(content) {
+            Ok(it) => it,
+            Err(e) => return Err(DecodeError::decompress(block, e)),
+        };
+        match stream::copy(&mut decoder, output_buf_file.writer) {
+            Ok(_) => Ok(()),
+            // mmap 缓存中正在写入的 block 没有结束帧, 与 zlib 一样保留已解压的内容
+            Err(CopyError::Read(err))
+                if self.options.mmap_buffer && err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Ok(())
             }
+            Err(CopyError::Read(err)) => Err(DecodeError::decompress(block, err)),
+            Err(CopyError::Write(err)) => Err(DecodeError::Io(err)),
         }
+    }
+}
 
-        Ok(())
+/// ECDH 的共享密钥即 TEA 密钥, 按小端读取
+fn key_agreement(client_pub_key: &PublicKey, key: &NamedKey) -> [u32; 4] {
+    let secret = key.private_key.shared_secret(client_pub_key);
+    let mut tea_key = [0; 4];
+    for (key, bytes) in tea_key.iter_mut().zip(secret.as_bytes().chunks(4)) {
+        *key = read_integer::<u32>(bytes);
     }
+    tea_key
 }
 
 impl Context {
     pub fn new(input: String, output: String, private_key: String) -> Context {
+        let options = DecodeOptions {
+            mmap_buffer: is_mmap_buffer(&input),
+            ..Default::default()
+        };
         Context {
             input,
             output,
-            private_key,
-            last_seq: 0,
+            decoder: Decoder::with_options(&private_key, options),
+            format: OutputFormat::Text,
+            filter: RecordFilter::default(),
         }
     }
 
-    pub fn decode(&mut self) -> anyhow::Result<()> {
-        let mut input_buf_file = InputBuffer::new(&self.input)?;
-        let in_buf_bytes = input_buf_file.bytes();
-
-        let mut start_pos: usize = 0;
-        match get_log_start_pos(in_buf_bytes, 2) {
-            Some(it) => start_pos = it,
-            None => return Err(anyhow::anyhow!("无效 Xlog 文件")),
-        };
+    pub fn set_format(&mut self, format: OutputFormat) {
+        self.format = format;
+    }
 
-        let mut output_buf_file = OutputBufFile::new(&self.output)?;
-        loop {
-            match self.decode_buf(&mut input_buf_file, &mut start_pos, &mut output_buf_file) {
-                Ok(pos) => {
-                    start_pos = pos;
-                }
-                Err(e) => {
-                    let root_cause = e.root_cause();
-                    if let Some(io_error) = root_cause.downcast_ref::<io::Error>() {
-                        if io_error.kind() == io::ErrorKind::UnexpectedEof {
-                            return Ok(());
-                        }
-                    }
-                    return Err(e);
-                }
-            }
-        }
+    /// 同时按 `filter` 的时间范围跳过不需要的 block
+    pub fn set_filter(&mut self, filter: RecordFilter) {
+        self.decoder.options.hours = filter.hour_window();
+        self.filter = filter;
     }
-}
 
-fn is_good_log_buf(buf: &[u8], offset: usize, count: i8) -> bool {
-    if offset == buf.len() {
-        return true;
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.decoder.options.parallel = parallel;
     }
-    let mut crypt_key_len: usize = 0;
-    let magic_value = buf[offset];
-    if magic::NO_COMPRESS_START == magic_value
-        || magic::COMPRESS_START == magic_value
-        || magic::COMPRESS_START1 == magic_value
-    {
-        crypt_key_len = 4;
-    } else if magic::COMPRESS_START2 == magic_value
-        || magic::NO_COMPRESS_START1 == magic_value
-        || magic::NO_COMPRESS_NO_CRYPT_START == magic_value
-        || magic::COMPRESS_NO_CRYPT_START == magic_value
-        || magic::SYNC_ZSTD_START == magic_value
-        || magic::SYNC_NO_CRYPT_ZSTD_START == magic_value
-        || magic::ASYNC_ZSTD_START == magic_value
-        || magic::ASYNC_NO_CRYPT_ZSTD_START == magic_value
-    {
-        crypt_key_len = 64;
-    } else {
-        return false;
+
+    /// 纯文本输出中 seq 缺失时插入的提示, 为 `None` 时不插入
+    pub fn set_gap_marker(&mut self, gap_marker: Option<String>) {
+        self.decoder.options.gap_marker = gap_marker;
     }
 
-    let header_len = 1 + 2 + 1 + 1 + 4 + crypt_key_len;
-    if (offset + header_len + 1 + 1) > buf.len() {
-        return false;
+    /// 解码出错时记录错误并继续, 见 `DecodeOptions::recover`
+    pub fn set_recover(&mut self, recover: bool) {
+        self.decoder.options.recover = recover;
     }
 
-    let start = offset + header_len - crypt_key_len - 4;
-    let end = start + 4;
-    let bytes = &buf[start..end];
-    let length = read_integer::<u32>(bytes) as usize;
-    if (offset + header_len + length + 1) > buf.len() {
-        return false;
+    /// 使用多个私钥解码, 见 `Decoder::with_keyring`
+    pub fn set_keyring(&mut self, keyring: Keyring) {
+        self.decoder.set_keyring(keyring);
     }
-    if magic::END != buf[offset + header_len + length] {
-        return false;
+
+    /// 输入是 mmap 缓存, 见 `DecodeOptions::mmap_buffer`, 默认按 `is_mmap_buffer` 判断
+    pub fn set_mmap_buffer(&mut self, mmap_buffer: bool) {
+        self.decoder.options.mmap_buffer = mmap_buffer;
     }
-    if count >= 1 {
-        return true;
+
+    /// `input` 为 `-` 时从标准输入读取, `output` 为 `-` 时写入标准输出
+    pub fn decode(&mut self) -> Result<DecodeReport, DecodeError> {
+        if STDIO_PATH == self.input {
+            let mut input = Vec::new();
+            io::stdin().lock().read_to_end(&mut input)?;
+            self.decode_input(&input, "stdin")?;
+        } else {
+            let in_file = File::open(&self.input)?;
+            let in_mmap = unsafe { Mmap::map(&in_file)? };
+            let source = std::path::Path::new(&self.input)
+                .file_name()
+                .map(|it| it.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            self.decode_input(&in_mmap, &source)?;
+        }
+        Ok(self.decoder.report().clone())
     }
-    return is_good_log_buf(buf, offset + header_len + length + 1, count - 1);
-}
 
-fn get_log_start_pos(buf: &[u8], count: i8) -> Option<usize> {
-    let mut offset: usize = 0;
-    loop {
-        if offset >= buf.len() {
-            break;
-        }
-        let magic_value = buf[offset];
-        if magic::NO_COMPRESS_START == magic_value
-            || magic::NO_COMPRESS_START1 == magic_value
-            || magic::COMPRESS_START == magic_value
-            || magic::COMPRESS_START1 == magic_value
-            || magic::COMPRESS_START2 == magic_value
-            || magic::COMPRESS_NO_CRYPT_START == magic_value
-            || magic::NO_COMPRESS_NO_CRYPT_START == magic_value
-            || magic::SYNC_ZSTD_START == magic_value
-            || magic::SYNC_NO_CRYPT_ZSTD_START == magic_value
-            || magic::ASYNC_ZSTD_START == magic_value
-            || magic::ASYNC_NO_CRYPT_ZSTD_START == magic_value
-        {
-            if is_good_log_buf(buf, offset, count) {
-                return Some(offset);
-            }
+    fn decode_input(&mut self, input: &[u8], source: &str) -> Result<(), DecodeError> {
+        if STDIO_PATH == self.output {
+            let stdout = io::stdout();
+            let output = BufWriter::new(stdout.lock());
+            return output::write_records(
+                &mut self.decoder,
+                input,
+                source,
+                self.format,
+                &self.filter,
+                output,
+            );
         }
-        offset += 1;
+
+        let out_file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&self.output)?;
+        let output = BufWriter::new(out_file);
+        output::write_records(
+            &mut self.decoder,
+            input,
+            source,
+            self.format,
+            &self.filter,
+            output,
+        )
     }
-    None
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use walkdir::WalkDir;
 
-    #[test]
-    fn decode_test() {
-        let pwd = std::env::var("PWD").unwrap();
-        println!("pwd: {:?}", pwd);
-        let sample_data_path = PathBuf::from(pwd).join("sample_data");
-        println!("sample_data_path: {:?}", sample_data_path);
+    use crate::test_utils::{load_sample_keys, sample_data_path, write_blocks};
 
-        let input = sample_data_path.join("encrypt_sample_data.xlog");
-
-        let output = sample_data_path.join("encrypt_sample_data.xlog.log");
+    /// 测试输出写入临时目录, 不修改 `sample_data`
+    fn temp_output(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.log", std::process::id(), name))
+    }
 
-        let env_path = sample_data_path.join("custom.env");
-        println!("env_path: {:?}", env_path);
-        dotenv::from_path(env_path.as_path()).ok().unwrap();
+    /// 通过 `Context` 解码到文件, 结果与 `decode_bytes` 相同
+    fn decode_file(input: &Path, private_key: &str) {
+        let name = input.file_name().unwrap().to_str().unwrap();
+        let output = temp_output(name);
+        let mut ctx = Context::new(
+            input.to_str().unwrap().to_string(),
+            output.to_str().unwrap().to_string(),
+            private_key.to_string(),
+        );
+        let result = ctx.decode();
+        let decoded = std::fs::read(&output);
+        std::fs::remove_file(&output).ok();
+        if let Err(e) = result {
+            panic!("{}: {:?}", name, e);
+        }
+        assert_eq!(
+            decoded.unwrap(),
+            decode_bytes(&std::fs::read(input).unwrap(), private_key).unwrap(),
+            "{}",
+            name
+        );
+    }
 
-        let private_key = std::env::var("TEST_XLOG_PRIVATE_KEY").unwrap_or("".to_string());
-        println!("private_key: {:?}", private_key);
+    #[test]
+    fn decode_test() {
+        let sample_data_path = sample_data_path();
+        let (private_key, _) = load_sample_keys();
 
-        let input_path = String::from(input.to_str().unwrap());
-        let output_path = String::from(output.to_str().unwrap());
-        let mut ctx = Context::new(input_path, output_path, private_key);
-        match ctx.decode() {
-            Ok(_) => println!("成功"),
-            Err(e) => {
-                assert!(false, "{:?}", e.root_cause());
-            }
-        }
+        decode_file(
+            &sample_data_path.join("encrypt_sample_data.xlog"),
+            &private_key,
+        );
     }
 
     #[test]
     fn decode_all_test() {
-        let pwd = std::env::var("PWD").unwrap();
-        println!("pwd: {:?}", pwd);
-        let sample_data_path = PathBuf::from(pwd).join("sample_data");
-        println!("sample_data_path: {:?}", sample_data_path);
-
-        let env_path = sample_data_path.join("custom.env");
-        println!("env_path: {:?}", env_path);
-        dotenv::from_path(env_path.as_path()).ok().unwrap();
+        let sample_data_path = sample_data_path();
+        let (private_key, _) = load_sample_keys();
 
+        let mut count = 0;
         for entry in WalkDir::new(sample_data_path) {
             let entry = entry.unwrap();
-            if entry.path().is_dir() {
+            let file_name = entry.file_name().to_str().unwrap();
+            if !file_name.starts_with('z') || entry.path().extension() != Some("xlog".as_ref()) {
                 continue;
             }
 
-            let file_name = entry.file_name().to_str().unwrap();
+            if file_name.contains("_crypt_") {
+                // 加密日志
+                decode_file(entry.path(), &private_key);
+            } else {
+                // 未加密日志
+                decode_file(entry.path(), "");
+            }
+            count += 1;
+        }
+        assert_eq!(count, 8);
+    }
 
-            let extension = entry.path().extension().unwrap().to_str();
+    #[test]
+    fn decode_bytes_test() {
+        let sample_data_path = sample_data_path();
+        let (private_key, _) = load_sample_keys();
+
+        for name in [
+            "zlib_async_crypt_20220110.xlog",
+            "zstd_async_no_crypt_20220110.xlog",
+        ] {
+            let input = std::fs::read(sample_data_path.join(name)).unwrap();
+            let key = if name.contains("_crypt_") {
+                private_key.as_str()
+            } else {
+                ""
+            };
+
+            let output = decode_bytes(&input, key).unwrap();
+            assert!(!output.is_empty(), "{}", name);
+            assert!(
+                String::from_utf8_lossy(&output).contains("MARS_PATH"),
+                "{}",
+                name
+            );
+
+            let mut reader_output = Vec::new();
+            decode_reader(io::Cursor::new(&input), &mut reader_output, key).unwrap();
+            assert_eq!(output, reader_output);
+        }
+    }
 
-            if !file_name.starts_with("z") || extension != Some("xlog") {
-                continue;
+    #[test]
+    fn decode_error_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zstd_async_crypt_20220110.xlog")).unwrap();
+
+        match decode_bytes(&input, "abc") {
+            Err(DecodeError::InvalidKey { .. }) => {}
+            other => panic!("{:?}", other),
+        }
+
+        let wrong_key = "01".repeat(32);
+        match decode_bytes(&input, &wrong_key) {
+            Err(DecodeError::WrongKey { offset, magic, .. }) => {
+                assert_eq!(offset, 0);
+                assert!(magic.is_crypt());
             }
+            other => panic!("{:?}", other),
+        }
 
-            let pwd = std::env::var("PWD").unwrap();
-            let sample_data_path = PathBuf::from(pwd).join("sample_data");
-            let mut output = sample_data_path.join(file_name);
-            output.set_extension("xlog.log");
+        match decode_bytes(b"not an xlog file", "") {
+            Err(DecodeError::NotXlog { len: 16 }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
 
-            let input_path = String::from(entry.path().to_str().unwrap());
-            let output_path = String::from(output.to_str().unwrap());
+    #[test]
+    fn decode_hours_test() {
+        use crate::encode::WriterConfig;
 
-            println!("input_path: {:?}", input_path);
-            println!("output_path: {:?}", output_path);
-            if file_name.contains("_crypt_") {
-                // 加密日志
-                let private_key = std::env::var("TEST_XLOG_PRIVATE_KEY").unwrap_or("".to_string());
-                println!("private_key: {:?}", private_key);
-                let mut ctx = Context::new(input_path, output_path, private_key);
-                match ctx.decode() {
-                    Ok(_) => println!("成功"),
-                    Err(e) => {
-                        assert!(false, "{:?}", e.root_cause());
-                    }
-                }
+        let mut input = write_blocks(WriterConfig::default(), [10, 12, 23]);
+
+        // 破坏 10 点的 block, 跳过时不应解压
+        let first = crate::block::blocks(&input).next().unwrap().unwrap();
+        for byte in &mut input[first.payload.clone()] {
+            *byte = 0xff;
+        }
+        assert!(decode_bytes(&input, "").is_err());
+
+        let mut decoder = Decoder::with_options(
+            "",
+            DecodeOptions {
+                hours: Some((11, 12)),
+                ..Default::default()
+            },
+        );
+        let mut output = Vec::new();
+        decoder.decode(&input, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "hour 12\n");
+
+        decoder.set_options(DecodeOptions {
+            hours: Some((22, 9)),
+            ..Default::default()
+        });
+        let mut hours = Vec::new();
+        decoder
+            .for_each_block(&input, |block, _| {
+                hours.push(block.begin_hour);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(hours, [23]);
+    }
+
+    #[test]
+    fn decode_parallel_test() {
+        use crate::encode::WriterConfig;
+
+        let (private_key, public_key) = load_sample_keys();
+
+        let config = WriterConfig {
+            pub_key: public_key,
+            ..Default::default()
+        };
+        let mut input = write_blocks(config, (0..200).map(|i| i % 24));
+
+        // 去掉一个 block 制造 seq 缺失, 并在末尾追加无法解析的数据
+        let blocks: Vec<XlogBlock> = crate::block::blocks(&input).map(|it| it.unwrap()).collect();
+        input.drain(blocks[100].offset..blocks[100].end());
+        input.extend_from_slice(&[0xff; 16]);
+
+        let decode = |parallel: bool| {
+            let mut decoder = Decoder::with_options(
+                &private_key,
+                DecodeOptions {
+                    parallel,
+                    ..Default::default()
+                },
+            );
+            let mut output = Vec::new();
+            let result = decoder.decode(&input, &mut output);
+            (output, result.map_err(|e| e.to_string()))
+        };
+        let (sequential, sequential_result) = decode(false);
+        let (parallel, parallel_result) = decode(true);
+        assert!(String::from_utf8_lossy(&sequential).contains("log seq:101-101 is missing"));
+        assert!(sequential_result.is_err());
+        assert_eq!(sequential, parallel);
+        assert_eq!(sequential_result, parallel_result);
+    }
+
+    #[test]
+    fn decode_gap_test() {
+        use crate::encode::WriterConfig;
+
+        let mut input = write_blocks(WriterConfig::default(), 10..15);
+
+        // 去掉 11 点和 12 点的 block
+        let blocks: Vec<XlogBlock> = crate::block::blocks(&input).map(|it| it.unwrap()).collect();
+        input.drain(blocks[1].offset..blocks[3].offset);
+        let gap = SeqGap {
+            expected: blocks[1].seq,
+            seen: blocks[3].seq,
+            offset: blocks[1].offset,
+            begin_hour: 13,
+            end_hour: 13,
+        };
+
+        let mut decoder = Decoder::new("");
+        let mut output = Vec::new();
+        decoder.decode(&input, &mut output).unwrap();
+        assert_eq!(decoder.report().blocks, 3);
+        assert_eq!(decoder.report().seq_gaps, [gap]);
+        assert_eq!(decoder.report().missing_blocks(), 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "hour 10\n[F]decode_log_file.py log seq:{}-{} is missing\nhour 13\nhour 14\n",
+                blocks[1].seq, blocks[2].seq
+            )
+        );
+
+        for (gap_marker, expected) in [
+            (None, String::from("hour 10\nhour 13\nhour 14\n")),
+            (
+                Some("lost {missing} at {offset}"),
+                format!(
+                    "hour 10\nlost 2 at {}\nhour 13\nhour 14\n",
+                    blocks[1].offset
+                ),
+            ),
+        ] {
+            decoder.set_options(DecodeOptions {
+                gap_marker: gap_marker.map(String::from),
+                ..Default::default()
+            });
+            let mut output = Vec::new();
+            decoder.decode(&input, &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+            assert_eq!(decoder.report().seq_gaps, [gap]);
+        }
+
+        decoder.for_each_block(&input, |_, _| Ok(())).unwrap();
+        assert_eq!(decoder.report().seq_gaps, [gap]);
+    }
+
+    #[test]
+    fn decode_recover_test() {
+        use crate::encode::{WriterConfig, XlogWriter};
+
+        let (private_key, public_key) = load_sample_keys();
+
+        for (private_key, public_key) in [("", ""), (private_key.as_str(), public_key.as_str())] {
+            let config = WriterConfig {
+                pub_key: String::from(public_key),
+                ..Default::default()
+            };
+            let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+            writer.write_log(10, b"first\n").unwrap();
+            writer.flush_block().unwrap();
+            writer.write_log(11, b"corrupt\n").unwrap();
+            writer.flush_block().unwrap();
+            for i in 0..200 {
+                writer
+                    .write_log(12, format!("crash {}\n", i).as_bytes())
+                    .unwrap();
+            }
+            let mut input = writer.finish().unwrap();
+            let full = decode_bytes(&input, private_key).unwrap();
+            let full = String::from_utf8(full).unwrap();
+
+            // 第二个 block 的 deflate 块类型改为非法的 11, 最后一个 block 截去一半
+            let blocks: Vec<XlogBlock> =
+                crate::block::blocks(&input).map(|it| it.unwrap()).collect();
+            if blocks[1].crypt {
+                let tea_key = Decoder::new(private_key).tea_key(&[], &blocks[1]).unwrap();
+                let payload = &mut input[blocks[1].payload.start..blocks[1].payload.start + 8];
+                utils::tea_decrypt_buf(payload, &tea_key);
+                payload[0] = 0x07;
+                utils::tea_encrypt_buf(payload, &tea_key);
             } else {
-                // 未加密日志
-                let mut ctx = Context::new(input_path, output_path, String::new());
-                match ctx.decode() {
-                    Ok(_) => println!("成功"),
-                    Err(e) => {
-                        assert!(false, "{:?}", e.root_cause());
-                    }
+                input[blocks[1].payload.start] = 0x07;
+            }
+            let last = &blocks[2];
+            input.truncate(last.payload.start + (last.payload.len() / 2));
+
+            assert!(decode_bytes(&input, private_key).is_err());
+
+            for parallel in [false, true] {
+                let mut decoder = Decoder::with_options(
+                    private_key,
+                    DecodeOptions {
+                        recover: true,
+                        parallel,
+                        ..Default::default()
+                    },
+                );
+                let mut output = Vec::new();
+                decoder.decode(&input, &mut output).unwrap();
+                let output = String::from_utf8(output).unwrap();
+                assert!(output.starts_with("first\ncrash 0\n"), "{}", output);
+                let salvaged = &output["first\n".len()..];
+                let crash = &full["first\ncorrupt\n".len()..];
+                assert!(crash.starts_with(salvaged) && salvaged.len() > 100);
+
+                let errors = &decoder.report().errors;
+                assert_eq!(errors.len(), 2, "{:?}", errors);
+                assert_eq!(errors[0].seq, Some(blocks[1].seq));
+                assert_eq!(errors[1].offset, Some(last.offset));
+                assert_eq!(decoder.report().blocks, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_recover_length_test() {
+        use crate::encode::WriterConfig;
+
+        let mut input = write_blocks(WriterConfig::default(), 10..15);
+
+        // 第二个 block 的长度改为超出文件末尾, 看起来与截断的最后一个 block 相同
+        let blocks: Vec<XlogBlock> = crate::block::blocks(&input).map(|it| it.unwrap()).collect();
+        let magic = blocks[1].magic;
+        let length = blocks[1].offset + magic.header_len() - magic.crypt_key_len() - 4;
+        input[length..length + 4].copy_from_slice(&0x00ff_ffffu32.to_le_bytes());
+
+        let expected = String::from_utf8(decode_bytes(&input, "").unwrap()).unwrap();
+        assert!(expected.starts_with("hour 10\n"));
+        assert!(expected.ends_with("hour 12\nhour 13\nhour 14\n"));
+
+        for parallel in [false, true] {
+            let mut decoder = Decoder::with_options(
+                "",
+                DecodeOptions {
+                    recover: true,
+                    parallel,
+                    ..Default::default()
+                },
+            );
+            let mut output = Vec::new();
+            decoder.decode(&input, &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+            let errors = &decoder.report().errors;
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            assert_eq!(errors[0].offset, Some(blocks[1].offset));
+            assert_eq!(decoder.report().blocks, 4);
+        }
+    }
+
+    #[test]
+    fn decode_mmap_buffer_test() {
+        use crate::encode::{CompressMode, WriterConfig, XlogWriter};
+
+        let (private_key, public_key) = load_sample_keys();
+
+        let logs: Vec<String> = (0..100).map(|i| format!("crash {}\n", i)).collect();
+        let mmap_len = 150 * 1024;
+        for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+            for (private_key, public_key) in [("", ""), (private_key.as_str(), public_key.as_str())]
+            {
+                let config = WriterConfig {
+                    compress_mode,
+                    pub_key: String::from(public_key),
+                    ..Default::default()
+                };
+                let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+                for log in &logs {
+                    writer.write_log(9, log.as_bytes()).unwrap();
+                }
+                let mut block = writer.finish().unwrap();
+                // mmap 缓存中的 block 还没有 END
+                block.pop();
+
+                let decode = |input: &[u8]| {
+                    let mut decoder = Decoder::with_options(
+                        private_key,
+                        DecodeOptions {
+                            mmap_buffer: true,
+                            ..Default::default()
+                        },
+                    );
+                    let mut output = Vec::new();
+                    decoder.decode(input, &mut output).unwrap();
+                    (String::from_utf8(output).unwrap(), decoder.report().clone())
+                };
+
+                // 之后的空间都是 0
+                let mut input = block.clone();
+                input.resize(mmap_len, 0);
+                assert!(decode_bytes(&input, private_key).is_err());
+                let (output, report) = decode(&input);
+                assert_eq!(output, logs.concat());
+                assert_eq!(report.blocks, 1);
+                assert!(report.errors.is_empty());
+
+                // 下一条日志写了一半, 还没有更新头部的长度
+                let mut input = block.clone();
+                input.extend_from_slice(&[0x5a; 7]);
+                input.resize(mmap_len, 0);
+                assert_eq!(decode(&input).0, logs.concat());
+
+                // 头部的长度超出缓存
+                let input = &block[..block.len() - 16];
+                let (output, report) = decode(input);
+                assert!(logs.concat().starts_with(&output));
+                assert_eq!(report.errors.len(), 1);
+
+                assert_eq!(decode(&vec![0; mmap_len]).0, "");
+            }
+        }
+
+        assert!(is_mmap_buffer("log/cache/app.mmap3"));
+        assert!(!is_mmap_buffer("log/app_20220110.xlog"));
+    }
+
+    #[test]
+    fn decode_keyring_test() {
+        use crate::encode::{CompressMode, WriterConfig, XlogWriter};
+
+        let old_key = micro_uecc_safe::KeyPair::generate().unwrap();
+        let new_key = micro_uecc_safe::KeyPair::generate().unwrap();
+        let wrong_key = micro_uecc_safe::KeyPair::generate().unwrap();
+
+        // 只有几个字节的 zlib 数据用错误的私钥也可能解压成功, 每个 block 写入多条日志,
+        // 压缩后也超过 `TeaReader` 一次解密的长度
+        let logs = |name: &str| -> Vec<String> {
+            (0..2000u32)
+                .map(|i| {
+                    format!(
+                        "{} key rotation {} {:08x}\n",
+                        name,
+                        i,
+                        i.wrapping_mul(2654435761)
+                    )
+                })
+                .collect()
+        };
+        let (before, after) = (logs("before").concat(), logs("after").concat());
+        for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+            // 私钥轮换前后写入的日志拼接在一起
+            let mut input = Vec::new();
+            for (key, log) in [(&old_key, &before), (&new_key, &after)] {
+                let config = WriterConfig {
+                    compress_mode,
+                    pub_key: key.public_key.to_hex(),
+                    ..Default::default()
+                };
+                let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+                writer.write_log(10, log.as_bytes()).unwrap();
+                writer.flush_block().unwrap();
+                writer.write_log(11, log.as_bytes()).unwrap();
+                input.extend(writer.finish().unwrap());
+            }
+            assert!(crate::block::blocks(&input).all(|it| it.unwrap().payload.len() > 8 * 1024));
+
+            let mut keyring = Keyring::new();
+            keyring.add("wrong", &wrong_key.private_key.to_hex());
+            keyring.add("new", &new_key.private_key.to_hex());
+            keyring.add("old", &old_key.private_key.to_hex());
+            let mut decoder = Decoder::with_keyring(keyring, DecodeOptions::default());
+            let mut output = Vec::new();
+            decoder.decode(&input, &mut output).unwrap();
+            assert_eq!(
+                output,
+                [before.as_str(), &before, &after, &after]
+                    .concat()
+                    .as_bytes()
+            );
+            let usages: Vec<_> = decoder
+                .report()
+                .keys
+                .iter()
+                .map(|it| (it.key_id.as_deref(), it.blocks))
+                .collect();
+            assert_eq!(usages, [(Some("old"), 2), (Some("new"), 2)]);
+
+            let mut keyring = Keyring::new();
+            keyring.add("wrong", &wrong_key.private_key.to_hex());
+            keyring.add("old", &old_key.private_key.to_hex());
+            let mut decoder = Decoder::with_keyring(keyring, DecodeOptions::default());
+            let mut output = Vec::new();
+            match decoder.decode(&input, &mut output) {
+                Err(DecodeError::NoMatchingKey {
+                    keys: 2, offset, ..
+                }) => {
+                    assert_eq!(output, [before.as_str(), &before].concat().as_bytes());
+                    assert!(offset > 0);
                 }
+                other => panic!("unexpected result: {:?}", other),
             }
         }
     }
+
+    #[test]
+    fn tea_key_cache_test() {
+        let sample_data_path = sample_data_path();
+        let (private_key, _) = load_sample_keys();
+        let input = std::fs::read(sample_data_path.join("zlib_async_crypt_20220110.xlog")).unwrap();
+
+        let mut client_pubkeys: Vec<[u8; 64]> = crate::block::blocks(&input)
+            .filter_map(|it| it.unwrap().client_pubkey)
+            .collect();
+        client_pubkeys.sort_unstable();
+        client_pubkeys.dedup();
+        assert!(!client_pubkeys.is_empty());
+
+        let mut decoder = Decoder::new(&private_key);
+        let mut first = Vec::new();
+        decoder.decode(&input, &mut first).unwrap();
+        assert_eq!(decoder.tea_keys.lock().unwrap().len(), client_pubkeys.len());
+
+        let mut second = Vec::new();
+        decoder.decode(&input, &mut second).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, decode_bytes(&input, &private_key).unwrap());
+    }
 }
@@ -0,0 +1,421 @@
+use flate2::{Compress, FlushCompress};
+use micro_uecc_safe::{KeyPair, PublicKey};
+use std::io;
+use std::io::Write;
+
+use crate::block::{magic, MagicKind};
+use crate::utils;
+
+/// 与 mars `TAppenderMode` 对应
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppenderMode {
+    Async,
+    Sync,
+}
+
+/// 与 mars `TCompressMode` 对应
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressMode {
+    Zlib,
+    Zstd,
+}
+
+/// 与 mars `XLogConfig` 中影响文件格式的字段对应
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    pub mode: AppenderMode,
+    pub compress_mode: CompressMode,
+    pub compress_level: i32,
+    /// 服务端公钥 hex, 为空时不加密
+    pub pub_key: String,
+}
+
+impl Default for WriterConfig {
+    fn default() -> WriterConfig {
+        WriterConfig {
+            mode: AppenderMode::Async,
+            compress_mode: CompressMode::Zlib,
+            compress_level: 6,
+            pub_key: String::new(),
+        }
+    }
+}
+
+/// mars 异步模式 mmap 缓存大小, 压缩后的日志超过该大小时自动生成一个 block
+const BUFFER_BLOCK_LENGTH: usize = 150 * 1024;
+
+/// 与 mars `LogCrypt` 一致, 每个 writer 生成一对临时的 client 密钥, 与服务端公钥协商出 TEA 密钥
+struct ClientCrypt {
+    client_pubkey: Vec<u8>,
+    tea_key: [u32; 4],
+}
+
+impl ClientCrypt {
+    fn new(pub_key: &str) -> io::Result<ClientCrypt> {
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid pub_key: {}", reason),
+            )
+        };
+        let server_pubkey = PublicKey::from_hex(pub_key).map_err(|e| invalid(e.to_string()))?;
+
+        let pair = KeyPair::generate().ok_or_else(|| io::Error::other("生成 client 密钥失败"))?;
+        let client_pubkey = pair.public_key.as_bytes().to_vec();
+        let secret = pair.private_key.shared_secret(&server_pubkey);
+
+        let mut tea_key = [0; 4];
+        for (key, bytes) in tea_key.iter_mut().zip(secret.as_bytes().chunks(4)) {
+            *key = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Ok(ClientCrypt {
+            client_pubkey,
+            tea_key,
+        })
+    }
+}
+
+/// 异步模式下尚未写出的 block
+struct PendingBlock {
+    begin_hour: u8,
+    end_hour: u8,
+    compressor: BlockCompressor,
+}
+
+/// 与 mars `LogZlibBuffer` / `LogZstdBuffer` 一致, 每条日志写入后都 flush 压缩流
+enum BlockCompressor {
+    /// raw deflate, 以 `Z_SYNC_FLUSH` 结尾, 没有结束块
+    Zlib(Compress, Vec<u8>),
+    /// block 写出时结束 zstd 帧
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl BlockCompressor {
+    fn new(config: &WriterConfig) -> io::Result<BlockCompressor> {
+        match config.compress_mode {
+            CompressMode::Zlib => {
+                let level = flate2::Compression::new(config.compress_level.clamp(0, 9) as u32);
+                Ok(BlockCompressor::Zlib(
+                    Compress::new(level, false),
+                    Vec::new(),
+                ))
+            }
+            CompressMode::Zstd => {
+                let encoder = zstd::stream::write::Encoder::new(Vec::new(), config.compress_level)?;
+                Ok(BlockCompressor::Zstd(encoder))
+            }
+        }
+    }
+
+    fn write(&mut self, log: &[u8]) -> io::Result<()> {
+        match self {
+            BlockCompressor::Zlib(compress, output) => {
+                let start = compress.total_in();
+                loop {
+                    output.reserve(log.len() / 2 + 64);
+                    let consumed = (compress.total_in() - start) as usize;
+                    compress
+                        .compress_vec(&log[consumed..], output, FlushCompress::Sync)
+                        .map_err(io::Error::other)?;
+                    // 输出还有剩余空间时 sync flush 已经完成
+                    let consumed = (compress.total_in() - start) as usize;
+                    if consumed == log.len() && output.len() < output.capacity() {
+                        return Ok(());
+                    }
+                }
+            }
+            BlockCompressor::Zstd(encoder) => {
+                encoder.write_all(log)?;
+                encoder.flush()
+            }
+        }
+    }
+
+    /// 已经压缩的字节数
+    fn len(&self) -> usize {
+        match self {
+            BlockCompressor::Zlib(_, output) => output.len(),
+            BlockCompressor::Zstd(encoder) => encoder.get_ref().len(),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            BlockCompressor::Zlib(_, output) => Ok(output),
+            BlockCompressor::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// 生成与 mars `appender_open` 格式一致的 xlog 文件
+///
+/// 同步模式每条日志单独生成一个 block, 异步模式的日志压缩后缓存, 在 `flush` 或缓存写满时生成一个 block,
+/// 结束前需要调用 `finish` 或 `flush`, 否则缓存的日志会丢失
+pub struct XlogWriter<W: Write> {
+    inner: W,
+    config: WriterConfig,
+    crypt: Option<ClientCrypt>,
+    seq: u16,
+    pending: Option<PendingBlock>,
+}
+
+impl<W: Write> XlogWriter<W> {
+    /// `config.pub_key` 不为空时按 mars 的方式加密, 公钥不合法时返回 `InvalidInput`
+    pub fn new(inner: W, config: WriterConfig) -> io::Result<XlogWriter<W>> {
+        let crypt = if config.pub_key.is_empty() {
+            None
+        } else {
+            Some(ClientCrypt::new(&config.pub_key)?)
+        };
+        Ok(XlogWriter {
+            inner,
+            config,
+            crypt,
+            seq: 0,
+            pending: None,
+        })
+    }
+
+    pub fn magic(&self) -> MagicKind {
+        let is_crypt = self.crypt.is_some();
+        match (self.config.mode, self.config.compress_mode, is_crypt) {
+            (AppenderMode::Async, CompressMode::Zlib, true) => MagicKind::AsyncZlib,
+            (AppenderMode::Async, CompressMode::Zlib, false) => MagicKind::AsyncNoCryptZlib,
+            (AppenderMode::Async, CompressMode::Zstd, true) => MagicKind::AsyncZstd,
+            (AppenderMode::Async, CompressMode::Zstd, false) => MagicKind::AsyncNoCryptZstd,
+            (AppenderMode::Sync, CompressMode::Zlib, true) => MagicKind::SyncZlib,
+            (AppenderMode::Sync, CompressMode::Zlib, false) => MagicKind::SyncNoCryptZlib,
+            (AppenderMode::Sync, CompressMode::Zstd, true) => MagicKind::SyncZstd,
+            (AppenderMode::Sync, CompressMode::Zstd, false) => MagicKind::SyncNoCryptZstd,
+        }
+    }
+
+    /// 加密时写入 block 头部的 client 公钥
+    pub fn client_pubkey(&self) -> Option<&[u8]> {
+        self.crypt.as_ref().map(|it| it.client_pubkey.as_slice())
+    }
+
+    /// 写入一条已经格式化好的日志, `hour` 为日志产生时的小时
+    pub fn write_log(&mut self, hour: u8, log: &[u8]) -> io::Result<()> {
+        if AppenderMode::Sync == self.config.mode {
+            return self.write_block(0, hour, hour, log);
+        }
+
+        if self.pending.is_none() {
+            self.pending = Some(PendingBlock {
+                begin_hour: hour,
+                end_hour: hour,
+                compressor: BlockCompressor::new(&self.config)?,
+            });
+        }
+        let pending = self.pending.as_mut().unwrap();
+        pending.end_hour = hour;
+        pending.compressor.write(log)?;
+        if pending.compressor.len() >= BUFFER_BLOCK_LENGTH {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// 将异步模式缓存的日志压缩写出为一个 block
+    pub fn flush_block(&mut self) -> io::Result<()> {
+        let pending = match self.pending.take() {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        let mut payload = pending.compressor.finish()?;
+        if let Some(crypt) = &self.crypt {
+            utils::tea_encrypt_buf(&mut payload, &crypt.tea_key);
+        }
+        let seq = self.next_seq();
+        self.write_block(seq, pending.begin_hour, pending.end_hour, &payload)
+    }
+
+    /// 写出缓存的日志并 flush 内部的 `Write`
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.inner.flush()
+    }
+
+    /// 写出剩余的日志并返回内部的 `Write`
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// 与 mars 一致, 异步模式的 seq 从 1 开始递增并跳过 0, 同步模式固定为 0
+    fn next_seq(&mut self) -> u16 {
+        self.seq = self.seq.wrapping_add(1);
+        if self.seq == 0 {
+            self.seq = 1;
+        }
+        self.seq
+    }
+
+    fn write_block(
+        &mut self,
+        seq: u16,
+        begin_hour: u8,
+        end_hour: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let magic_value = self.magic();
+        let length = payload.len() as u32;
+
+        let mut header = Vec::with_capacity(magic_value.header_len());
+        header.push(magic_value.value());
+        header.extend_from_slice(&seq.to_le_bytes());
+        header.push(begin_hour);
+        header.push(end_hour);
+        header.extend_from_slice(&length.to_le_bytes());
+        if let Some(crypt) = &self.crypt {
+            header.extend_from_slice(&crypt.client_pubkey);
+        }
+        header.resize(magic_value.header_len(), 0);
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(payload)?;
+        self.inner.write_all(&[magic::END])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::block::blocks;
+    use crate::decode::decode_bytes;
+    use crate::test_utils::load_sample_keys;
+
+    #[test]
+    fn write_round_trip_test() {
+        let logs = ["first line\n", "second line\n", "第三行\n"];
+        for mode in [AppenderMode::Async, AppenderMode::Sync] {
+            for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+                let config = WriterConfig {
+                    mode,
+                    compress_mode,
+                    ..Default::default()
+                };
+                let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+                let magic_value = writer.magic();
+                for (hour, log) in logs.iter().enumerate() {
+                    writer.write_log(10 + hour as u8, log.as_bytes()).unwrap();
+                }
+                let output = writer.finish().unwrap();
+
+                let parsed: Vec<_> = blocks(&output).map(|it| it.unwrap()).collect();
+                assert!(parsed.iter().all(|it| it.magic == magic_value));
+                if AppenderMode::Sync == mode {
+                    assert_eq!(parsed.len(), logs.len());
+                    assert!(parsed.iter().all(|it| it.seq == 0));
+                } else {
+                    assert_eq!(parsed.len(), 1);
+                    assert_eq!(parsed[0].seq, 1);
+                    assert_eq!((parsed[0].begin_hour, parsed[0].end_hour), (10, 12));
+                }
+
+                let decoded = decode_bytes(&output, "").unwrap();
+                assert_eq!(String::from_utf8(decoded).unwrap(), logs.concat());
+            }
+        }
+    }
+
+    #[test]
+    fn write_block_length_test() {
+        // 与 mars 一致, zlib 压缩流以 sync flush 结尾, 没有结束块
+        let mut writer = XlogWriter::new(Vec::new(), WriterConfig::default()).unwrap();
+        writer.write_log(10, b"hello\n").unwrap();
+        let output = writer.finish().unwrap();
+        let block = blocks(&output).next().unwrap().unwrap();
+        assert!(output[block.payload].ends_with(&[0x00, 0x00, 0xff, 0xff]));
+
+        // 缓存大小按压缩后的长度计算, 容易压缩的日志超过该大小也只生成一个 block
+        let repeated = "the same log line\n".repeat(64);
+        let random: Vec<String> = (0..1200u32)
+            .map(|i| {
+                (0..64u32)
+                    .map(|j| format!("{:08x}", (i * 64 + j).wrapping_mul(2654435761) ^ j))
+                    .collect::<String>()
+                    + "\n"
+            })
+            .collect();
+        for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+            let config = WriterConfig {
+                compress_mode,
+                ..Default::default()
+            };
+            let mut writer = XlogWriter::new(Vec::new(), config.clone()).unwrap();
+            for _ in 0..400 {
+                writer.write_log(10, repeated.as_bytes()).unwrap();
+            }
+            let output = writer.finish().unwrap();
+            assert!(repeated.len() * 400 > BUFFER_BLOCK_LENGTH);
+            assert_eq!(blocks(&output).count(), 1);
+            assert_eq!(
+                decode_bytes(&output, "").unwrap(),
+                repeated.repeat(400).as_bytes()
+            );
+
+            let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+            for log in &random {
+                writer.write_log(10, log.as_bytes()).unwrap();
+            }
+            let output = writer.finish().unwrap();
+            let parsed: Vec<_> = blocks(&output).map(|it| it.unwrap()).collect();
+            assert!(parsed.len() > 1);
+            for block in &parsed[..parsed.len() - 1] {
+                assert!(block.payload.len() >= BUFFER_BLOCK_LENGTH);
+            }
+            assert_eq!(
+                decode_bytes(&output, "").unwrap(),
+                random.concat().as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn write_crypt_round_trip_test() {
+        let (private_key, public_key) = load_sample_keys();
+
+        // 覆盖 payload 末尾不足 8 字节的情况
+        let logs: Vec<String> = (0..64)
+            .map(|i| format!("log line {}\n", "x".repeat(i)))
+            .collect();
+        for mode in [AppenderMode::Async, AppenderMode::Sync] {
+            for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+                let config = WriterConfig {
+                    mode,
+                    compress_mode,
+                    pub_key: public_key.clone(),
+                    ..Default::default()
+                };
+                let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+                let client_pubkey = writer.client_pubkey().unwrap().to_vec();
+                for log in &logs {
+                    writer.write_log(8, log.as_bytes()).unwrap();
+                }
+                let output = writer.finish().unwrap();
+
+                for block in blocks(&output) {
+                    let block = block.unwrap();
+                    assert!(block.magic.is_crypt_variant());
+                    assert_eq!(block.client_pubkey.unwrap().to_vec(), client_pubkey);
+                }
+
+                let decoded = decode_bytes(&output, &private_key).unwrap();
+                assert_eq!(String::from_utf8(decoded).unwrap(), logs.concat());
+            }
+        }
+
+        let config = WriterConfig {
+            pub_key: String::from("1234"),
+            ..Default::default()
+        };
+        assert!(XlogWriter::new(Vec::new(), config).is_err());
+    }
+}
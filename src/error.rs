@@ -0,0 +1,152 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::block::{Compression, MagicKind, XlogBlock};
+
+/// 解码过程中的错误
+///
+/// 与某个 block 相关的错误都带有该 block 的起始位置、seq 以及 magic
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// 输入中找不到任何合法的 block
+    #[error("not an xlog file: no valid block in {len} bytes")]
+    NotXlog { len: usize },
+
+    /// `offset` 之后再也找不到合法的 block
+    #[error("no valid block after offset {offset}: {remaining} bytes left")]
+    NoValidBlock { offset: usize, remaining: usize },
+
+    /// 数据在 block 内部被截断
+    #[error("truncated block at offset {offset} (seq {seq:?}, magic {magic}): need {needed} bytes, {available} available")]
+    Truncated {
+        offset: usize,
+        seq: Option<u16>,
+        magic: MagicKind,
+        needed: usize,
+        available: usize,
+    },
+
+    /// 私钥不是合法的 32 字节 hex 字符串
+    #[error("invalid private key: {reason}")]
+    InvalidKey { reason: String },
+
+    /// block 头部的 client 公钥无法完成 ECDH
+    #[error("ECDH failed for block at offset {offset} (seq {seq}, magic {magic})")]
+    KeyAgreement {
+        offset: usize,
+        seq: u16,
+        magic: MagicKind,
+    },
+
+    /// 解密后的数据无法解压, 通常是私钥不匹配
+    #[error("wrong private key for block at offset {offset} (seq {seq}, magic {magic}): {source}")]
+    WrongKey {
+        offset: usize,
+        seq: u16,
+        magic: MagicKind,
+        #[source]
+        source: io::Error,
+    },
+
+    /// 密钥环中的私钥都无法解出合法的压缩数据
+    #[error(
+        "none of {keys} private keys decrypts block at offset {offset} (seq {seq}, magic {magic})"
+    )]
+    NoMatchingKey {
+        offset: usize,
+        seq: u16,
+        magic: MagicKind,
+        keys: usize,
+    },
+
+    /// 压缩数据损坏
+    #[error("corrupt {compression:?} stream in block at offset {offset} (seq {seq}, magic {magic}): {source}")]
+    Decompress {
+        offset: usize,
+        seq: u16,
+        magic: MagicKind,
+        compression: Compression,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl DecodeError {
+    pub(crate) fn truncated(block: &XlogBlock, needed: usize, available: usize) -> DecodeError {
+        DecodeError::Truncated {
+            offset: block.offset,
+            seq: Some(block.seq),
+            magic: block.magic,
+            needed,
+            available,
+        }
+    }
+
+    pub(crate) fn key_agreement(block: &XlogBlock) -> DecodeError {
+        DecodeError::KeyAgreement {
+            offset: block.offset,
+            seq: block.seq,
+            magic: block.magic,
+        }
+    }
+
+    pub(crate) fn decompress(block: &XlogBlock, source: io::Error) -> DecodeError {
+        if block.crypt {
+            DecodeError::WrongKey {
+                offset: block.offset,
+                seq: block.seq,
+                magic: block.magic,
+                source,
+            }
+        } else {
+            DecodeError::Decompress {
+                offset: block.offset,
+                seq: block.seq,
+                magic: block.magic,
+                compression: block.compression,
+                source,
+            }
+        }
+    }
+
+    /// 出错 block 的起始位置
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            DecodeError::NoValidBlock { offset, .. }
+            | DecodeError::Truncated { offset, .. }
+            | DecodeError::KeyAgreement { offset, .. }
+            | DecodeError::WrongKey { offset, .. }
+            | DecodeError::NoMatchingKey { offset, .. }
+            | DecodeError::Decompress { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// 出错 block 的 seq
+    pub fn seq(&self) -> Option<u16> {
+        match self {
+            DecodeError::Truncated { seq, .. } => *seq,
+            DecodeError::KeyAgreement { seq, .. }
+            | DecodeError::WrongKey { seq, .. }
+            | DecodeError::NoMatchingKey { seq, .. }
+            | DecodeError::Decompress { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+
+    /// 出错 block 的 magic
+    pub fn magic(&self) -> Option<MagicKind> {
+        match self {
+            DecodeError::Truncated { magic, .. }
+            | DecodeError::KeyAgreement { magic, .. }
+            | DecodeError::WrongKey { magic, .. }
+            | DecodeError::NoMatchingKey { magic, .. }
+            | DecodeError::Decompress { magic, .. } => Some(*magic),
+            _ => None,
+        }
+    }
+}
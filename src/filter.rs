@@ -0,0 +1,328 @@
+use regex::Regex;
+use std::str::FromStr;
+
+use crate::record::{LogEntry, LogLevel, LogTime};
+
+/// `--since` / `--until` 的时间, 只给出时分秒时按每天的时间比较
+///
+/// 支持 `14:00`, `14:00:30`, `14:00:30.500`, `2022-01-10 14:00` 等格式, 与日志中记录的本地时间比较
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBound {
+    pub date: Option<(i32, u8, u8)>,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl TimeBound {
+    fn date_key(time: &LogTime) -> (i32, u8, u8) {
+        (time.year, time.month, time.day)
+    }
+
+    fn time_key(time: &LogTime) -> (u8, u8, u8, u16) {
+        (time.hour, time.minute, time.second, time.millis)
+    }
+
+    fn time_of_day(&self) -> (u8, u8, u8, u16) {
+        (self.hour, self.minute, self.second, self.millis)
+    }
+
+    fn cmp_time(&self, time: &LogTime) -> std::cmp::Ordering {
+        let this = self.time_of_day();
+        match self.date {
+            Some(date) => (date, this).cmp(&(Self::date_key(time), Self::time_key(time))),
+            None => this.cmp(&Self::time_key(time)),
+        }
+    }
+}
+
+impl FromStr for TimeBound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeBound, String> {
+        let invalid = || {
+            format!(
+                "invalid time {:?}, expected [YYYY-MM-DD ]HH:MM[:SS[.mmm]]",
+                s
+            )
+        };
+        let s = s.trim();
+        let (date, time) = match s.rsplit_once([' ', 'T']) {
+            Some((date, time)) => (Some(date.trim()), time),
+            None => (None, s),
+        };
+
+        let date = match date {
+            Some(date) => {
+                let parts: Vec<&str> = date.split('-').collect();
+                if parts.len() != 3 {
+                    return Err(invalid());
+                }
+                Some((
+                    parts[0].parse().map_err(|_| invalid())?,
+                    parts[1].parse().map_err(|_| invalid())?,
+                    parts[2].parse().map_err(|_| invalid())?,
+                ))
+            }
+            None => None,
+        };
+
+        let (time, millis) = match time.split_once('.') {
+            Some((time, millis)) if millis.len() == 3 => {
+                (time, millis.parse().map_err(|_| invalid())?)
+            }
+            Some(_) => return Err(invalid()),
+            None => (time, 0),
+        };
+        let parts: Vec<&str> = time.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(invalid());
+        }
+        let bound = TimeBound {
+            date,
+            hour: parts[0].parse().map_err(|_| invalid())?,
+            minute: parts[1].parse().map_err(|_| invalid())?,
+            second: match parts.get(2) {
+                Some(second) => second.parse().map_err(|_| invalid())?,
+                None => 0,
+            },
+            millis,
+        };
+        if bound.hour > 23 || bound.minute > 59 || bound.second > 59 || bound.millis > 999 {
+            return Err(invalid());
+        }
+        Ok(bound)
+    }
+}
+
+/// 按解析后的日志过滤, 多行日志作为一个整体保留或丢弃
+#[derive(Debug, Clone, Default)]
+pub struct RecordFilter {
+    /// 最低日志级别
+    pub level: Option<LogLevel>,
+    /// 任意一个 tag 相同即保留, 为空时不过滤
+    pub tags: Vec<String>,
+    /// 包含该时间
+    pub since: Option<TimeBound>,
+    /// 不包含该时间, 都只有时分秒且 `until` 早于 `since` 时表示跨过零点
+    pub until: Option<TimeBound>,
+    /// 匹配完整的一条日志, 包括日志头
+    pub grep: Option<Regex>,
+    /// 保留不匹配 `grep` 的日志
+    pub invert: bool,
+}
+
+impl RecordFilter {
+    /// 没有设置任何条件
+    pub fn is_empty(&self) -> bool {
+        self.level.is_none()
+            && self.tags.is_empty()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.grep.is_none()
+    }
+
+    /// 是否有依赖日志头的条件, 有时无法解析日志头的内容会被丢弃
+    fn has_header_condition(&self) -> bool {
+        self.level.is_some()
+            || !self.tags.is_empty()
+            || self.since.is_some()
+            || self.until.is_some()
+    }
+
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        let record = match entry {
+            LogEntry::Record(record) => record,
+            LogEntry::Text(text) => return !self.has_header_condition() && self.matches_grep(text),
+        };
+
+        if let Some(level) = self.level {
+            if record.level < level {
+                return false;
+            }
+        }
+        if !self.tags.is_empty() && !self.tags.contains(&record.tag) {
+            return false;
+        }
+        if !self.matches_time(&record.time) {
+            return false;
+        }
+        if self.grep.is_some() {
+            return self.matches_grep(&record.to_string());
+        }
+        true
+    }
+
+    fn matches_time(&self, time: &LogTime) -> bool {
+        let after_since = match &self.since {
+            Some(since) => since.cmp_time(time).is_le(),
+            None => true,
+        };
+        let before_until = match &self.until {
+            Some(until) => until.cmp_time(time).is_gt(),
+            None => true,
+        };
+        if self.is_overnight() {
+            after_since || before_until
+        } else {
+            after_since && before_until
+        }
+    }
+
+    fn is_overnight(&self) -> bool {
+        match (&self.since, &self.until) {
+            (Some(since), Some(until)) => {
+                since.date.is_none()
+                    && until.date.is_none()
+                    && since.time_of_day() > until.time_of_day()
+            }
+            _ => false,
+        }
+    }
+
+    /// `since`/`until` 覆盖的小时范围 (包含两端), 用于跳过不需要解码的 block
+    ///
+    /// 带日期的时间只有在两端日期相同时才能换算为小时范围, 否则返回 `None`
+    pub fn hour_window(&self) -> Option<(u8, u8)> {
+        let since_date = self.since.and_then(|it| it.date);
+        let until_date = self.until.and_then(|it| it.date);
+        if since_date.is_some() || until_date.is_some() {
+            let same_date =
+                self.since.is_some() && self.until.is_some() && since_date == until_date;
+            if !same_date {
+                return None;
+            }
+        }
+
+        let begin = self.since.map(|it| it.hour);
+        let end = self.until.map(|until| {
+            // until 不包含在内, 整点时不需要该小时的 block
+            if until.minute == 0 && until.second == 0 && until.millis == 0 {
+                (until.hour + 23) % 24
+            } else {
+                until.hour
+            }
+        });
+        match (begin, end) {
+            (None, None) => None,
+            // 跨夜且两端在同一小时内, 需要的是除这一小时中间一段外的全天
+            (Some(begin), Some(end)) if begin == end && self.is_overnight() => None,
+            (begin, end) => Some((begin.unwrap_or(0), end.unwrap_or(23))),
+        }
+    }
+
+    fn matches_grep(&self, text: &str) -> bool {
+        match &self.grep {
+            Some(grep) => grep.is_match(text) != self.invert,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::record::parse_entries;
+
+    #[test]
+    fn filter_test() {
+        let text = "get mmap time: 1\n\
+            [I][2022-01-10 +8.0 13:59:59.999][1, 1*][net][a.cpp:1, f][before\n\
+            [E][2022-01-10 +8.0 14:00:00.000][1, 2][net][a.cpp:2, f][timeout\nretry 1\n\
+            [E][2022-01-10 +8.0 14:10:00.000][1, 2][ui][a.cpp:3, f][crash\n\
+            [W][2022-01-10 +8.0 14:20:00.000][1, 2][net][a.cpp:4, f][slow\n\
+            [E][2022-01-10 +8.0 14:30:00.000][1, 2][net][a.cpp:5, f][after\n";
+        let entries = parse_entries(text);
+        let select = |filter: &RecordFilter| -> Vec<String> {
+            entries
+                .iter()
+                .filter(|it| filter.matches(it))
+                .map(|it| match it {
+                    LogEntry::Record(record) => record.message.clone(),
+                    LogEntry::Text(text) => text.clone(),
+                })
+                .collect()
+        };
+
+        assert_eq!(select(&RecordFilter::default()).len(), entries.len());
+
+        let filter = RecordFilter {
+            level: Some(LogLevel::Error),
+            tags: vec![String::from("net")],
+            since: Some("14:00".parse().unwrap()),
+            until: Some("2022-01-10 14:30".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(select(&filter), ["timeout\nretry 1"]);
+
+        let filter = RecordFilter {
+            grep: Some(Regex::new("retry|mmap").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(select(&filter), ["get mmap time: 1", "timeout\nretry 1"]);
+
+        let filter = RecordFilter {
+            tags: vec![String::from("net")],
+            grep: Some(Regex::new("a.cpp:[12],").unwrap()),
+            invert: true,
+            ..Default::default()
+        };
+        assert_eq!(select(&filter), ["slow", "after"]);
+
+        let filter = RecordFilter {
+            since: Some("14:15".parse().unwrap()),
+            until: Some("14:05".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            select(&filter),
+            ["before", "timeout\nretry 1", "slow", "after"]
+        );
+        assert_eq!(filter.hour_window(), None);
+
+        assert!("25:00".parse::<TimeBound>().is_err());
+        assert!("14".parse::<TimeBound>().is_err());
+        assert!("14:00:00.5".parse::<TimeBound>().is_err());
+        assert_eq!(
+            "2022-01-10T14:00:30.500".parse::<TimeBound>().unwrap(),
+            TimeBound {
+                date: Some((2022, 1, 10)),
+                hour: 14,
+                minute: 0,
+                second: 30,
+                millis: 500,
+            }
+        );
+    }
+
+    #[test]
+    fn hour_window_test() {
+        let window = |since: Option<&str>, until: Option<&str>| {
+            RecordFilter {
+                since: since.map(|it| it.parse().unwrap()),
+                until: until.map(|it| it.parse().unwrap()),
+                ..Default::default()
+            }
+            .hour_window()
+        };
+        assert_eq!(window(None, None), None);
+        assert_eq!(window(Some("14:00"), Some("14:30")), Some((14, 14)));
+        assert_eq!(window(Some("14:00"), Some("16:00")), Some((14, 15)));
+        assert_eq!(window(Some("22:30"), Some("02:10")), Some((22, 2)));
+        assert_eq!(window(Some("14:15"), Some("14:05")), None);
+        assert_eq!(window(Some("14:15"), Some("14:00")), Some((14, 13)));
+        assert_eq!(window(Some("22:30"), None), Some((22, 23)));
+        assert_eq!(window(None, Some("00:00")), Some((0, 23)));
+        assert_eq!(
+            window(Some("2022-01-10 09:00"), Some("2022-01-10 10:30")),
+            Some((9, 10))
+        );
+        assert_eq!(
+            window(Some("2022-01-10 23:00"), Some("2022-01-11 01:00")),
+            None
+        );
+        assert_eq!(window(Some("2022-01-10 23:00"), None), None);
+    }
+}
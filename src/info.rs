@@ -0,0 +1,244 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::block::{self, MagicKind, SeqGap};
+use crate::error::DecodeError;
+use crate::utils;
+
+/// 某种 magic 的 block 数量
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MagicCount {
+    pub magic: &'static str,
+    pub value: u8,
+    pub blocks: usize,
+}
+
+/// 因数据损坏跳过的区域
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorruptRegion {
+    pub offset: usize,
+    pub len: usize,
+    pub reason: String,
+}
+
+/// xlog 文件的结构信息, 只解析 block 头部, 不解密也不解压
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct XlogInfo {
+    /// 输入的字节数
+    pub len: usize,
+    pub blocks: usize,
+    /// 按 magic 的值排序
+    pub magics: Vec<MagicCount>,
+    /// 非 0 seq 的最小值与最大值, 同步模式写入的 block seq 都为 0
+    pub seq_range: Option<(u16, u16)>,
+    /// 与解码时输出 `log seq:..-.. is missing` 的规则一致
+    pub seq_gaps: Vec<SeqGap>,
+    /// 第一个 block 的 begin_hour 与最后一个 block 的 end_hour
+    pub hours: Option<(u8, u8)>,
+    /// 加密变体 block 头部的 client 公钥, hex 格式, 按出现顺序去重
+    pub client_pubkeys: Vec<String>,
+    pub corrupt: Vec<CorruptRegion>,
+}
+
+impl XlogInfo {
+    /// 遍历 `input` 的所有 block, 找不到任何合法 block 时返回 `NotXlog`
+    pub fn from_bytes(input: &[u8]) -> Result<XlogInfo, DecodeError> {
+        let mut info = XlogInfo {
+            len: input.len(),
+            ..Default::default()
+        };
+        let mut magics: Vec<(MagicKind, usize)> = Vec::new();
+        let mut last_seq: u16 = 0;
+        for block in block::blocks(input) {
+            let block = match block {
+                Ok(it) => it,
+                Err(DecodeError::NotXlog { len }) => return Err(DecodeError::NotXlog { len }),
+                Err(e) => {
+                    if let Some(offset) = e.offset() {
+                        info.corrupt.push(CorruptRegion {
+                            offset,
+                            len: input.len() - offset,
+                            reason: e.to_string(),
+                        });
+                    }
+                    break;
+                }
+            };
+
+            info.blocks += 1;
+            if block.skipped > 0 {
+                info.corrupt.push(CorruptRegion {
+                    offset: block.offset - block.skipped,
+                    len: block.skipped,
+                    reason: String::from("no valid block header"),
+                });
+            }
+            match magics.iter_mut().find(|(magic, _)| *magic == block.magic) {
+                Some((_, count)) => *count += 1,
+                None => magics.push((block.magic, 1)),
+            }
+
+            info.seq_gaps.extend(block::seq_gap(&mut last_seq, &block));
+            let seq = block.seq;
+            if seq != 0 {
+                info.seq_range = match info.seq_range {
+                    Some((min, max)) => Some((min.min(seq), max.max(seq))),
+                    None => Some((seq, seq)),
+                };
+            }
+
+            info.hours = match info.hours {
+                Some((begin, _)) => Some((begin, block.end_hour)),
+                None => Some((block.begin_hour, block.end_hour)),
+            };
+            if let Some(pubkey) = &block.client_pubkey {
+                let pubkey = utils::encode_hex(pubkey);
+                if !info.client_pubkeys.contains(&pubkey) {
+                    info.client_pubkeys.push(pubkey);
+                }
+            }
+        }
+
+        magics.sort();
+        info.magics = magics
+            .into_iter()
+            .map(|(magic, blocks)| MagicCount {
+                magic: magic.name(),
+                value: magic.value(),
+                blocks,
+            })
+            .collect();
+        Ok(info)
+    }
+}
+
+impl fmt::Display for XlogInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<10}{} bytes", "size:", self.len)?;
+        writeln!(f, "{:<10}{}", "blocks:", self.blocks)?;
+        for (i, it) in self.magics.iter().enumerate() {
+            let label = if i == 0 { "magic:" } else { "" };
+            writeln!(
+                f,
+                "{:<10}0x{:02x} {} ({} blocks)",
+                label, it.value, it.magic, it.blocks
+            )?;
+        }
+        match self.seq_range {
+            Some((min, max)) => writeln!(f, "{:<10}{}-{}", "seq:", min, max)?,
+            None => writeln!(f, "{:<10}-", "seq:")?,
+        }
+        if self.seq_gaps.is_empty() {
+            writeln!(f, "{:<10}none", "missing:")?;
+        }
+        for (i, it) in self.seq_gaps.iter().enumerate() {
+            let label = if i == 0 { "missing:" } else { "" };
+            writeln!(
+                f,
+                "{:<10}{}-{} (before offset {})",
+                label,
+                it.expected,
+                it.last(),
+                it.offset
+            )?;
+        }
+        match self.hours {
+            Some((begin, end)) => writeln!(f, "{:<10}{:02}-{:02}", "hours:", begin, end)?,
+            None => writeln!(f, "{:<10}-", "hours:")?,
+        }
+        if self.client_pubkeys.is_empty() {
+            writeln!(f, "{:<10}none", "pubkeys:")?;
+        }
+        for (i, it) in self.client_pubkeys.iter().enumerate() {
+            let label = if i == 0 { "pubkeys:" } else { "" };
+            writeln!(f, "{:<10}{}", label, it)?;
+        }
+        if self.corrupt.is_empty() {
+            writeln!(f, "{:<10}none", "corrupt:")?;
+        }
+        for (i, it) in self.corrupt.iter().enumerate() {
+            let label = if i == 0 { "corrupt:" } else { "" };
+            writeln!(
+                f,
+                "{:<10}{}..{} ({} bytes): {}",
+                label,
+                it.offset,
+                it.offset + it.len,
+                it.len,
+                it.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::encode::WriterConfig;
+    use crate::test_utils::{sample_data_path, write_blocks};
+
+    #[test]
+    fn info_test() {
+        let input = write_blocks(WriterConfig::default(), [14, 15, 16]);
+        let expected: Vec<_> = block::blocks(&input).map(|it| it.unwrap()).collect();
+        assert_eq!(expected.len(), 3);
+
+        // 去掉第二个 block, 并在第一个 block 前和文件末尾加入垃圾数据
+        let mut corrupted = vec![0xff; 5];
+        corrupted.extend_from_slice(&input[..expected[0].end()]);
+        corrupted.extend_from_slice(&input[expected[2].offset..]);
+        corrupted.extend_from_slice(&[0xff; 16]);
+
+        let info = XlogInfo::from_bytes(&corrupted).unwrap();
+        assert_eq!(info.len, corrupted.len());
+        assert_eq!(info.blocks, 2);
+        assert_eq!(
+            info.magics,
+            [MagicCount {
+                magic: expected[0].magic.name(),
+                value: expected[0].magic.value(),
+                blocks: 2,
+            }]
+        );
+        assert_eq!(info.seq_range, Some((expected[0].seq, expected[2].seq)));
+        assert_eq!(
+            info.seq_gaps,
+            [SeqGap {
+                expected: expected[1].seq,
+                seen: expected[2].seq,
+                offset: expected[0].end() + 5,
+                begin_hour: 16,
+                end_hour: 16,
+            }]
+        );
+        assert_eq!(info.hours, Some((14, 16)));
+        assert!(info.client_pubkeys.is_empty());
+        assert_eq!(info.corrupt.len(), 2);
+        assert_eq!((info.corrupt[0].offset, info.corrupt[0].len), (0, 5));
+        assert_eq!(info.corrupt[1].len, 16);
+        assert_eq!(info.corrupt[1].offset + 16, corrupted.len());
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["blocks"], 2);
+        assert_eq!(json["hours"], serde_json::json!([14, 16]));
+
+        assert!(matches!(
+            XlogInfo::from_bytes(&[0xff; 64]),
+            Err(DecodeError::NotXlog { len: 64 })
+        ));
+    }
+
+    #[test]
+    fn info_pubkey_test() {
+        let sample_data_path = sample_data_path();
+        let input = std::fs::read(sample_data_path.join("zstd_async_crypt_20220110.xlog")).unwrap();
+        let info = XlogInfo::from_bytes(&input).unwrap();
+        assert!(info.magics.iter().any(|it| it.magic == "async-zlib-crypt"));
+        assert!(!info.client_pubkeys.is_empty());
+        assert!(info.client_pubkeys.iter().all(|it| it.len() == 128));
+        assert!(info.corrupt.is_empty());
+        assert!(info.to_string().contains(&info.client_pubkeys[0]));
+    }
+}
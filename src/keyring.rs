@@ -0,0 +1,266 @@
+use micro_uecc_safe::{KeyError, PrivateKey};
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+use crate::block::XlogBlock;
+use crate::error::DecodeError;
+use crate::utils;
+
+/// 在 io 错误信息前加上出错的路径
+fn with_path(path: &Path, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), format!("{:?}: {}", path, error))
+}
+
+/// 带 id 的服务端私钥, id 只用于提示解密时使用了哪个私钥
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedKey {
+    pub id: String,
+    pub private_key: PrivateKey,
+}
+
+/// 由 hex 格式的私钥计算 hex 格式的公钥, 与 mars `XLogConfig::pub_key_` 的格式相同
+pub fn derive_public_key(private_key: &str) -> Result<String, DecodeError> {
+    let key = PrivateKey::from_hex(private_key).map_err(|e| DecodeError::InvalidKey {
+        reason: e.to_string(),
+    })?;
+    Ok(key.public_key().to_hex())
+}
+
+/// 多个服务端私钥, 解码时为每个 client 公钥找出能解密的私钥
+///
+/// 服务端轮换过私钥时, 新旧 xlog 文件可以用同一个密钥环解码
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keyring {
+    keys: Vec<NamedKey>,
+    /// 不合法的私钥的 id 及原因, 由 `validate` 报告
+    invalid: Vec<(String, KeyError)>,
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring::default()
+    }
+
+    /// 只有一个私钥的密钥环, id 为 `key-1`, 私钥为空时返回空的密钥环
+    pub fn single(private_key: &str) -> Keyring {
+        let mut keyring = Keyring::new();
+        keyring.add("key-1", private_key);
+        keyring
+    }
+
+    /// 添加一个 hex 格式的私钥, 空私钥及与已有私钥相同的私钥会被忽略
+    ///
+    /// 私钥在添加时解析, 不合法的私钥不会加入密钥环, 由 `validate` 报告
+    pub fn add<S: Into<String>>(&mut self, id: S, private_key: &str) {
+        let private_key = private_key.trim();
+        if private_key.is_empty() {
+            return;
+        }
+        match PrivateKey::from_hex(private_key) {
+            Ok(private_key) => {
+                if self.keys.iter().all(|it| it.private_key != private_key) {
+                    self.keys.push(NamedKey {
+                        id: id.into(),
+                        private_key,
+                    });
+                }
+            }
+            Err(e) => self.invalid.push((id.into(), e)),
+        }
+    }
+
+    /// 添加 `id=私钥` 或只有私钥的一项, 没有 id 时使用 `default_id`
+    ///
+    /// 与 dotenv 格式兼容, 忽略 `export` 前缀及值两侧的引号
+    pub fn add_entry(&mut self, entry: &str, default_id: &str) {
+        let entry = entry.trim();
+        let entry = entry.strip_prefix("export ").unwrap_or(entry);
+        match entry.split_once('=') {
+            Some((id, private_key)) => {
+                let private_key = private_key.trim().trim_matches(|c| c == '"' || c == '\'');
+                self.add(id.trim(), private_key)
+            }
+            None => self.add(default_id, entry),
+        }
+    }
+
+    /// 每行一个私钥, 忽略空行及 `#` 开头的注释
+    ///
+    /// 没有 id 的私钥: 只有一个时 id 为 `name`, 否则为 `name:行号`;
+    /// `sample_data/custom.env` 这类 dotenv 文件中 id 以 `PUBLIC_KEY` 结尾的公钥也会被忽略
+    pub fn add_lines(&mut self, content: &str, name: &str) {
+        let entries: Vec<_> = content
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .filter(|(_, line)| {
+                !line
+                    .split_once('=')
+                    .is_some_and(|(id, _)| id.trim().to_ascii_uppercase().ends_with("PUBLIC_KEY"))
+            })
+            .collect();
+        let unnamed = entries.iter().filter(|(_, it)| !it.contains('=')).count();
+        for (line_no, entry) in entries {
+            if unnamed == 1 {
+                self.add_entry(entry, name);
+            } else {
+                self.add_entry(entry, &format!("{}:{}", name, line_no));
+            }
+        }
+    }
+
+    /// 读取私钥文件, 格式见 `add_lines`, 默认 id 为文件名, 读取失败时错误信息中带有路径
+    pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+        let content = std::fs::read_to_string(path).map_err(|e| with_path(path, e))?;
+        let name = path
+            .file_name()
+            .map(|it| it.to_string_lossy())
+            .unwrap_or_default();
+        self.add_lines(&content, &name);
+        Ok(())
+    }
+
+    /// 读取目录下的所有私钥文件, 不递归, 按文件名排序, 忽略隐藏文件
+    pub fn load_dir(&mut self, dir: &Path) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(|e| with_path(dir, e))? {
+            let path = entry.map_err(|e| with_path(dir, e))?.path();
+            let visible = path
+                .file_name()
+                .is_some_and(|it| !it.to_string_lossy().starts_with('.'));
+            if path.is_file() && visible {
+                files.push(path);
+            }
+        }
+        files.sort();
+        for path in files {
+            self.load_file(&path)?;
+        }
+        Ok(())
+    }
+
+    /// 返回第一个不合法的私钥的错误, 错误信息中带有 id
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        match self.invalid.first() {
+            Some((id, e)) => Err(DecodeError::InvalidKey {
+                reason: format!("{}: {}", id, e),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn keys(&self) -> &[NamedKey] {
+        &self.keys
+    }
+
+    /// 合法的私钥数量
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// 没有合法的私钥
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// 一个 client 公钥及解密其 block 的私钥
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyUsage {
+    /// hex 格式
+    pub client_pubkey: String,
+    /// 解密成功的私钥 id, 没有私钥能解密时为 `None`
+    pub key_id: Option<String>,
+    /// 使用该公钥的加密 block 数量
+    pub blocks: usize,
+}
+
+impl KeyUsage {
+    /// 统计加密 block 的 client 公钥, 按出现顺序
+    pub(crate) fn count(usages: &mut Vec<KeyUsage>, block: &XlogBlock) {
+        let pubkey = match &block.client_pubkey {
+            Some(it) if block.crypt => utils::encode_hex(it),
+            _ => return,
+        };
+        match usages.iter_mut().find(|it| it.client_pubkey == pubkey) {
+            Some(it) => it.blocks += 1,
+            None => usages.push(KeyUsage {
+                client_pubkey: pubkey,
+                key_id: None,
+                blocks: 1,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyring_test() {
+        let key1 = "11".repeat(32);
+        let key2 = "22".repeat(32);
+        let key3 = "33".repeat(32);
+
+        let mut keyring = Keyring::single("");
+        assert!(keyring.is_empty());
+        keyring.add_entry(&key1, "key-1");
+        keyring.add_entry(&format!("2021 = {}", key2), "key-2");
+        // 重复的私钥只保留第一个
+        keyring.add_entry(&key1.to_uppercase(), "key-3");
+        let ids: Vec<_> = keyring.keys().iter().map(|it| it.id.as_str()).collect();
+        assert_eq!(ids, ["key-1", "2021"]);
+
+        let mut keyring = Keyring::new();
+        keyring.add_lines(&format!("# old\n{}\n\n", key1), "old.key");
+        keyring.add_lines(
+            &format!("export XLOG_PRIVATE_KEY=\"{}\"\n{}\n{}\n", key1, key2, key3),
+            "keys.env",
+        );
+        let ids: Vec<_> = keyring.keys().iter().map(|it| it.id.as_str()).collect();
+        assert_eq!(ids, ["old.key", "keys.env:2", "keys.env:3"]);
+        assert_eq!(keyring.keys()[2].private_key.to_hex(), key3);
+        assert!(keyring.validate().is_ok());
+
+        keyring.add("short", "abcd");
+        assert_eq!(keyring.len(), 3);
+        assert!(matches!(
+            keyring.validate(),
+            Err(DecodeError::InvalidKey { reason }) if reason == "short: expected 32 bytes, got 2"
+        ));
+
+        let missing = Path::new("missing-dir/xlog.key");
+        let error = keyring.load_file(missing).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("\"missing-dir/xlog.key\": "));
+        let error = keyring.load_dir(Path::new("missing-dir")).unwrap_err();
+        assert!(error.to_string().starts_with("\"missing-dir\": "));
+    }
+
+    #[test]
+    fn derive_public_key_test() {
+        let pair = micro_uecc_safe::KeyPair::generate().unwrap();
+        let public_key = derive_public_key(&pair.private_key.to_hex()).unwrap();
+        assert_eq!(public_key, pair.public_key.to_hex());
+        assert_eq!(public_key.len(), 128);
+
+        // 奇数长度, 非 hex 字符, 长度不对, 0 以及大于等于曲线阶 n 的私钥
+        let n = "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+        for private_key in ["abc", "zz", "abcd", &"00".repeat(32), n] {
+            assert!(
+                matches!(
+                    derive_public_key(private_key),
+                    Err(DecodeError::InvalidKey { .. })
+                ),
+                "{}",
+                private_key
+            );
+        }
+        let mut keyring = Keyring::single(n);
+        keyring.add("ok", &pair.private_key.to_hex());
+        assert!(keyring.validate().is_err());
+    }
+}
@@ -0,0 +1,36 @@
+//! Tencent Mars Xlog 解码库
+//!
+//! ```no_run
+//! let input = std::fs::read("sample.xlog").unwrap();
+//! let output = tencent_mars_xlog::decode_bytes(&input, "private key hex").unwrap();
+//! ```
+pub mod block;
+pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod filter;
+pub mod info;
+pub mod keygen;
+pub mod keyring;
+pub mod output;
+pub mod record;
+mod stream;
+#[cfg(test)]
+mod test_utils;
+mod utils;
+pub mod verify;
+
+pub use block::{blocks, BlockIter, Compression, MagicKind, SeqGap, XlogBlock};
+pub use decode::{
+    decode_bytes, decode_reader, Context, DecodeOptions, DecodeReport, Decoder, RecoveredError,
+    DEFAULT_GAP_MARKER,
+};
+pub use encode::{AppenderMode, CompressMode, WriterConfig, XlogWriter};
+pub use error::DecodeError;
+pub use filter::{RecordFilter, TimeBound};
+pub use info::XlogInfo;
+pub use keygen::{KeyFormat, Snippet};
+pub use keyring::{derive_public_key, KeyUsage, Keyring, NamedKey};
+pub use output::{OutputFormat, RecordWriter};
+pub use record::{LogEntry, LogLevel, LogRecord, LogTime};
+pub use verify::{verify, verify_keyring, VerifyReport, VerifyStatus};
@@ -1,11 +1,21 @@
-use clap::{AppSettings, Parser, Subcommand};
+use clap::{AppSettings, Args, Parser, Subcommand};
 use path_absolutize::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::io;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-use micro_uecc_safe;
-mod decode;
+use regex::Regex;
+use serde::Serialize;
+use tencent_mars_xlog::keygen::GeneratedKeyPair;
+use tencent_mars_xlog::{decode, verify};
+use tencent_mars_xlog::{
+    DecodeError, DecodeReport, KeyFormat, KeyUsage, Keyring, LogLevel, OutputFormat, RecordFilter,
+    Snippet, TimeBound, XlogInfo,
+};
 /// tencent-mars-xlog-util CLI
 #[derive(Parser)]
 #[clap(name = "tencent-mars-xlog-util")]
@@ -20,90 +30,644 @@ pub struct Cli {
 enum Commands {
     /// Generate the key
     #[clap(setting(AppSettings::AllArgsOverrideSelf))]
-    GenKey,
+    GenKey {
+        /// Key encoding: hex, base64, sec1, sec1-compressed, pem
+        #[clap(short, long, default_value = "hex")]
+        format: KeyFormat,
+
+        /// Write the private key to this new file with 0600 permissions instead of printing it
+        #[clap(long, parse(from_os_str))]
+        private_key_file: Option<PathBuf>,
+
+        /// Write the public key to this new file
+        #[clap(long, parse(from_os_str))]
+        public_key_file: Option<PathBuf>,
+
+        /// Also print a config snippet: ios, android, dotenv (not with --private-key-file), can be repeated
+        #[clap(long, multiple_occurrences = true)]
+        snippet: Vec<Snippet>,
+    },
+
+    /// Private key tools
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommands,
+    },
 
     /// Decode Xlog
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
     Decode {
-        /// Input file or Input dir
+        /// Input file or Input dir, `-` for stdin
         #[clap(short, long, required = true, parse(from_os_str))]
         input: PathBuf,
 
-        /// Output file or Output dif
+        /// Output file or Output dir (created for an input dir, keeping its subdirectories), `-` for stdout
         #[clap(short, long, required = true, parse(from_os_str))]
         output: PathBuf,
 
-        /// Private Key
-        #[clap(short, long)]
-        key: Option<String>,
+        #[clap(flatten)]
+        keys: KeyArgs,
+
+        /// Output format: text, jsonl, csv, tsv
+        ///
+        /// Only text output without filters streams each block to the output,
+        /// other formats and filters hold one decoded block in memory at a time
+        #[clap(short, long, default_value = "text")]
+        format: OutputFormat,
+
+        /// Minimum log level: V, D, I, W, E, F
+        #[clap(long)]
+        level: Option<LogLevel>,
+
+        /// Only keep logs with this tag, can be repeated
+        #[clap(long, multiple_occurrences = true)]
+        tag: Vec<String>,
+
+        /// Keep logs at or after this local time: [YYYY-MM-DD ]HH:MM[:SS[.mmm]]
+        #[clap(long)]
+        since: Option<TimeBound>,
+
+        /// Keep logs before this local time: [YYYY-MM-DD ]HH:MM[:SS[.mmm]]
+        #[clap(long)]
+        until: Option<TimeBound>,
+
+        /// Keep logs matching this regex, including the log header
+        #[clap(long)]
+        grep: Option<Regex>,
+
+        /// Keep logs not matching --grep
+        #[clap(long, requires = "grep")]
+        invert: bool,
+
+        /// Decrypt and decompress blocks of a file in parallel,
+        /// holding a batch of decoded blocks in memory
+        #[clap(long)]
+        parallel: bool,
+
+        /// Number of files decoded concurrently when the input is a dir, 0 for all cores
+        #[clap(short, long, default_value = "1")]
+        jobs: usize,
+
+        /// Line inserted into text output when log seqs are missing,
+        /// placeholders: {first}, {last}, {missing}, {offset}
+        #[clap(long, default_value = decode::DEFAULT_GAP_MARKER)]
+        gap_marker: String,
+
+        /// Do not insert a line into text output when log seqs are missing
+        #[clap(long, conflicts_with = "gap-marker")]
+        no_gap_marker: bool,
+
+        /// Skip blocks that fail to decrypt or decompress and salvage a truncated last block
+        #[clap(long)]
+        recover: bool,
+
+        /// Decode input as a mars mmap cache buffer, .mmap3 and .mmap2 files are detected automatically
+        #[clap(long)]
+        mmap: bool,
+
+        /// Write block counts and missing log seqs of every file to this JSON file
+        #[clap(long, parse(from_os_str))]
+        report: Option<PathBuf>,
+    },
+
+    /// Show the block structure of an xlog file without decoding
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Info {
+        /// Input file, `-` for stdin
+        #[clap(short, long, required = true, parse(from_os_str))]
+        input: PathBuf,
+
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Decode without writing output and report every problem found
+    ///
+    /// Exit codes: 0 ok, 1 unreadable input, 2 corrupt, 3 wrong or missing key, 4 not an xlog.
+    /// For a dir the most severe result is used.
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Verify {
+        /// Input file or Input dir, `-` for stdin
+        #[clap(short, long, required = true, parse(from_os_str))]
+        input: PathBuf,
+
+        #[clap(flatten)]
+        keys: KeyArgs,
+    },
+}
+
+/// 私钥相关的子命令
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Print the public key of a private key, the value of XLogConfig::pub_key_ in the app
+    DerivePublic {
+        /// Private key as hex, read from stdin when omitted
+        private_key: Option<String>,
+
+        /// Exit with code 1 unless the derived public key equals this one
+        #[clap(long)]
+        expect: Option<String>,
     },
 }
 
+/// 私钥相关参数, 所有私钥合并为一个密钥环
+#[derive(Args)]
+struct KeyArgs {
+    /// Private key as hex or `id=hex`, can be repeated
+    #[clap(short, long, multiple_occurrences = true)]
+    key: Vec<String>,
+
+    /// File with one private key per line as hex or `id=hex`, can be repeated
+    #[clap(long, multiple_occurrences = true, parse(from_os_str))]
+    key_file: Vec<PathBuf>,
+
+    /// Dir of private key files
+    #[clap(long, parse(from_os_str))]
+    key_dir: Option<PathBuf>,
+}
+
+impl KeyArgs {
+    /// 加载并检查所有私钥
+    fn keyring(&self) -> Result<Keyring, DecodeError> {
+        let mut keyring = Keyring::new();
+        for (i, key) in self.key.iter().enumerate() {
+            keyring.add_entry(key, &format!("key-{}", i + 1));
+        }
+        for path in &self.key_file {
+            keyring.load_file(path)?;
+        }
+        if let Some(dir) = &self.key_dir {
+            keyring.load_dir(dir)?;
+        }
+        keyring.validate()?;
+        Ok(keyring)
+    }
+}
+
+/// 每个文件共用的解码参数
+struct DecodeSettings {
+    keyring: Keyring,
+    format: OutputFormat,
+    filter: RecordFilter,
+    parallel: bool,
+    gap_marker: Option<String>,
+    recover: bool,
+    mmap_buffer: bool,
+}
+
+/// `--report` 中一个文件的解码结果
+#[derive(Serialize)]
+struct FileReport {
+    input: PathBuf,
+    bytes_written: u64,
+    #[serde(flatten)]
+    report: DecodeReport,
+    missing_blocks: u64,
+    error: Option<String>,
+}
+
 impl Cli {
-    fn decode_single_file(&self, input: &PathBuf, output: &PathBuf, private_key: String) {
+    /// 解码单个文件, 出错时输出到标准错误
+    fn decode_file(&self, input: &Path, output: &Path, settings: &DecodeSettings) -> FileReport {
+        let result = self.decode_single_file(input, output, settings);
+        if let Err(e) = &result {
+            eprintln!("{:?}: {:?}", input, e);
+        }
+        if let Ok((_, report)) = &result {
+            for usage in &report.keys {
+                eprintln!("{:?}: {}", input, describe_key(usage));
+            }
+            for error in &report.errors {
+                eprintln!("{:?}: recovered: {}", input, error.message);
+            }
+        }
+        let (bytes_written, report, error) = match result {
+            Ok((written, report)) => (written, report, None),
+            Err(e) => (0, DecodeReport::default(), Some(e.to_string())),
+        };
+        FileReport {
+            input: input.to_path_buf(),
+            bytes_written,
+            missing_blocks: report.missing_blocks(),
+            report,
+            error,
+        }
+    }
+
+    /// 解码单个文件, 返回写入的字节数及统计信息
+    fn decode_single_file(
+        &self,
+        input: &Path,
+        output: &Path,
+        settings: &DecodeSettings,
+    ) -> Result<(u64, DecodeReport), DecodeError> {
+        let format = settings.format;
         let input_path = String::from(input.to_str().unwrap());
         let mut output_path = String::from(output.to_str().unwrap());
         if output.is_dir() {
-            let file_name = input.file_name().unwrap();
-            let mut path = PathBuf::from(output.to_str().unwrap()).join(file_name);
-            path.set_extension("xlog.log");
+            let file_name = match input_path.as_str() {
+                decode::STDIO_PATH => OsStr::new("stdin"),
+                _ => input.file_name().unwrap(),
+            };
+            let path = output_file(output, Path::new(file_name), format);
             output_path = String::from(path.to_str().unwrap());
         }
-        let mut ctx = decode::Context::new(input_path, output_path, private_key);
-        let e = match ctx.decode() {
-            Err(it) => it,
-            _ => return,
+        let mut ctx = decode::Context::new(input_path, output_path.clone(), String::new());
+        ctx.set_keyring(settings.keyring.clone());
+        ctx.set_format(format);
+        ctx.set_filter(settings.filter.clone());
+        ctx.set_parallel(settings.parallel);
+        ctx.set_gap_marker(settings.gap_marker.clone());
+        ctx.set_recover(settings.recover);
+        if settings.mmap_buffer {
+            ctx.set_mmap_buffer(true);
+        }
+        let report = ctx.decode()?;
+        if decode::STDIO_PATH == output_path {
+            return Ok((0, report));
+        }
+        Ok((std::fs::metadata(&output_path)?.len(), report))
+    }
+
+    /// 校验单个文件并输出发现的问题, 返回退出码
+    fn verify_single_file(&self, input: &Path, keyring: &Keyring) -> i32 {
+        let report = match read_input(input) {
+            Ok(input) => verify::verify_keyring(&input, keyring.clone()),
+            Err(e) => {
+                println!("{:?}: {}", input, e);
+                return 1;
+            }
+        };
+        let status = report.status();
+        println!("{:?}: {} ({} blocks)", input, status, report.blocks);
+        for usage in &report.keys {
+            println!("  {}", describe_key(usage));
+        }
+        for issue in &report.issues {
+            println!("  {}", issue);
+        }
+        status.exit_code()
+    }
+
+    /// 使用 `jobs` 个线程解码目录下的所有文件
+    ///
+    /// 输出到目录时在其中按输入的相对路径创建输出文件, 开始解码前检查输出目录
+    fn decode_dir(
+        &self,
+        input: &Path,
+        output: &Path,
+        settings: &DecodeSettings,
+        jobs: usize,
+    ) -> Result<Vec<FileReport>, String> {
+        let files = collect_files(input);
+
+        // 都写入标准输出时按顺序解码, 避免内容交错
+        let (jobs, outputs) = if Path::new(decode::STDIO_PATH) == output {
+            (1, vec![output.to_path_buf(); files.len()])
+        } else {
+            (
+                jobs,
+                prepare_outputs(input, &files, output, settings.format)?,
+            )
         };
-        // std::panic::panic_any(e);
-        println!("{:?}", e);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .unwrap();
+        let results: Vec<FileReport> = pool.install(|| {
+            files
+                .par_iter()
+                .zip(&outputs)
+                .map(|(input_path, output_path)| {
+                    eprintln!("decode: {:?}", input_path);
+                    self.decode_file(input_path, output_path, settings)
+                })
+                .collect()
+        });
+
+        let failed = results.iter().filter(|it| it.error.is_some()).count();
+        let written: u64 = results.iter().map(|it| it.bytes_written).sum();
+        eprintln!(
+            "succeeded: {}, failed: {}, bytes written: {}",
+            results.len() - failed,
+            failed,
+            written
+        );
+        Ok(results)
     }
 }
 
 impl Cli {
     pub fn execute(&self) {
         match &self.command {
-            Commands::GenKey => {
-                if let Some(pair) = micro_uecc_safe::gen_secp2561k1_key_pair() {
-                    println!("private_key: {}", pair.private_key);
-                    println!("public_key: {}", pair.public_key);
-                } else {
-                    println!("生成失败")
+            Commands::GenKey {
+                format,
+                private_key_file,
+                public_key_file,
+                snippet,
+            } => {
+                // 私钥写入文件就是为了不输出到 stdout
+                if private_key_file.is_some() && snippet.contains(&Snippet::Dotenv) {
+                    eprintln!("--snippet dotenv prints the private key and can't be used with --private-key-file");
+                    std::process::exit(1);
                 }
-            }
-            Commands::Decode { input, output, key } => {
-                let input_path_buf = input.absolutize().unwrap().to_path_buf();
-                let out_path_buf = output.absolutize().unwrap().to_path_buf();
-                println!("input: {:?}", input_path_buf);
-                println!("output: {:?}", out_path_buf);
-
-                if input_path_buf.is_file() {
-                    let mut private_key = String::new();
-                    if let Some(key) = key {
-                        private_key.push_str(key);
+                let pair = match GeneratedKeyPair::generate() {
+                    Some(it) => it,
+                    None => {
+                        println!("生成失败");
+                        std::process::exit(1);
                     }
-
-                    self.decode_single_file(&input_path_buf, &out_path_buf, private_key);
-                    return;
-                } else {
-                    for entry in WalkDir::new(input_path_buf.as_path()) {
-                        let entry = entry.unwrap();
-                        if entry.path().is_dir() || entry.path().ends_with(".DS_Store") {
-                            continue;
-                        }
-                        let input_path = PathBuf::from(entry.path());
-                        println!("decode: {:?}", input_path);
-                        let mut private_key = String::new();
-                        if let Some(key) = key {
-                            private_key.push_str(key);
+                };
+                let private_key = pair.private_key(*format);
+                let public_key = pair.public_key(*format);
+                let files = [
+                    (private_key_file, &private_key, true),
+                    (public_key_file, &public_key, false),
+                ];
+                let mut written = Vec::new();
+                for (path, key, private) in files {
+                    if let Some(path) = path {
+                        if let Err(e) = write_key_file(path, key, private) {
+                            eprintln!("{:?}: {}", path, e);
+                            // 不留下缺少另一半的密钥文件
+                            for path in written {
+                                let _ = std::fs::remove_file(path);
+                            }
+                            std::process::exit(1);
                         }
+                        written.push(path);
+                    }
+                }
 
-                        self.decode_single_file(&input_path, &out_path_buf, private_key);
+                match private_key_file {
+                    Some(path) => println!("private_key: written to {:?}", path),
+                    None => print_key("private_key", &private_key, *format),
+                }
+                print_key("public_key", &public_key, *format);
+                for it in snippet {
+                    println!("\n# {}", it);
+                    print!("{}", pair.snippet(*it));
+                }
+            }
+            Commands::Key { command } => match command {
+                KeyCommands::DerivePublic {
+                    private_key,
+                    expect,
+                } => derive_public(private_key.as_deref(), expect.as_deref()),
+            },
+            Commands::Decode {
+                input,
+                output,
+                keys,
+                format,
+                level,
+                tag,
+                since,
+                until,
+                grep,
+                invert,
+                parallel,
+                jobs,
+                gap_marker,
+                no_gap_marker,
+                recover,
+                mmap,
+                report,
+            } => {
+                let keyring = keys.keyring().unwrap_or_else(|e| {
+                    eprintln!("failed to load private keys: {}", e);
+                    std::process::exit(1);
+                });
+                let settings = DecodeSettings {
+                    keyring,
+                    format: *format,
+                    filter: RecordFilter {
+                        level: *level,
+                        tags: tag.clone(),
+                        since: *since,
+                        until: *until,
+                        grep: grep.clone(),
+                        invert: *invert,
+                    },
+                    parallel: *parallel,
+                    gap_marker: if *no_gap_marker {
+                        None
+                    } else {
+                        Some(gap_marker.clone())
+                    },
+                    recover: *recover,
+                    mmap_buffer: *mmap,
+                };
+                let input_path_buf = absolutize(input);
+                let out_path_buf = absolutize(output);
+                // 输出可能是标准输出, 提示信息都写入标准错误
+                eprintln!("input: {:?}", input_path_buf);
+                eprintln!("output: {:?}", out_path_buf);
+
+                let results = if Path::new(decode::STDIO_PATH) == input_path_buf
+                    || input_path_buf.is_file()
+                {
+                    vec![self.decode_file(&input_path_buf, &out_path_buf, &settings)]
+                } else {
+                    self.decode_dir(&input_path_buf, &out_path_buf, &settings, *jobs)
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        })
+                };
+                if let Some(report) = report {
+                    let json = serde_json::to_string_pretty(&results).unwrap();
+                    if let Err(e) = std::fs::write(report, json) {
+                        eprintln!("{:?}: {}", report, e);
+                        std::process::exit(1);
+                    }
+                }
+                let failed = results.iter().filter(|it| it.error.is_some()).count();
+                if failed > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Commands::Verify { input, keys } => {
+                let keyring = keys.keyring().unwrap_or_else(|e| {
+                    eprintln!("failed to load private keys: {}", e);
+                    std::process::exit(1);
+                });
+                let input = absolutize(input);
+                let code = if Path::new(decode::STDIO_PATH) == input || input.is_file() {
+                    self.verify_single_file(&input, &keyring)
+                } else {
+                    collect_files(&input)
+                        .iter()
+                        .map(|it| self.verify_single_file(it, &keyring))
+                        .max()
+                        .unwrap_or(0)
+                };
+                std::process::exit(code);
+            }
+            Commands::Info { input, json } => {
+                let info = read_input(input)
+                    .map_err(DecodeError::from)
+                    .and_then(|input| XlogInfo::from_bytes(&input));
+                match info {
+                    Ok(info) if *json => {
+                        println!("{}", serde_json::to_string_pretty(&info).unwrap())
+                    }
+                    Ok(info) => print!("{}", info),
+                    Err(e) => {
+                        eprintln!("{:?}: {}", input, e);
+                        std::process::exit(1);
                     }
                 }
             }
         }
     }
 }
+/// PEM 自带标签, 其余格式与原来一样输出 `label: key`
+fn print_key(label: &str, key: &str, format: KeyFormat) {
+    if KeyFormat::Pem == format {
+        println!("{}", key);
+    } else {
+        println!("{}: {}", label, key);
+    }
+}
+
+/// 写入新的密钥文件, 不覆盖已有文件, 私钥文件只有所有者可以读写, 写入失败时删除该文件
+fn write_key_file(path: &Path, key: &str, private: bool) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(path)?;
+    let result = writeln!(file, "{}", key).and_then(|_| file.sync_all());
+    if result.is_err() {
+        drop(file);
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// 输出私钥对应的公钥, 与 `expect` 不同时以 1 退出
+fn derive_public(private_key: Option<&str>, expect: Option<&str>) {
+    let private_key = match private_key {
+        Some(it) => String::from(it),
+        None => {
+            let mut input = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut input) {
+                eprintln!("stdin: {}", e);
+                std::process::exit(1);
+            }
+            input
+        }
+    };
+    let public_key = match tencent_mars_xlog::derive_public_key(&private_key) {
+        Ok(it) => it,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("{}", public_key);
+    if let Some(expect) = expect {
+        if !public_key.eq_ignore_ascii_case(expect.trim()) {
+            eprintln!("public key does not match {}", expect);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// client 公钥只显示前 16 个字符
+fn describe_key(usage: &KeyUsage) -> String {
+    let pubkey = &usage.client_pubkey[..16];
+    match &usage.key_id {
+        Some(id) => format!(
+            "client public key {}.. decrypted with key {} ({} blocks)",
+            pubkey, id, usage.blocks
+        ),
+        None => format!(
+            "client public key {}.. matches no private key ({} blocks)",
+            pubkey, usage.blocks
+        ),
+    }
+}
+
+/// `-` 表示标准输入输出, 保持原样
+fn absolutize(path: &Path) -> PathBuf {
+    if Path::new(decode::STDIO_PATH) == path {
+        return path.to_path_buf();
+    }
+    path.absolutize().unwrap().to_path_buf()
+}
+
+/// 目录下的所有文件
+fn collect_files(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| !entry.path().is_dir() && !entry.path().ends_with(".DS_Store"))
+        .map(|entry| PathBuf::from(entry.path()))
+        .collect()
+}
+
+/// 输出目录下 `relative` 对应的输出文件, 扩展名由输出格式决定
+fn output_file(output_dir: &Path, relative: &Path, format: OutputFormat) -> PathBuf {
+    let mut path = output_dir.join(relative);
+    path.set_extension(format.extension());
+    path
+}
+
+/// 按输入目录下的相对路径确定每个文件的输出文件, 并创建所需的目录
+///
+/// 多个输入对应同一个输出文件时会相互覆盖, 直接返回错误
+fn prepare_outputs(
+    input_dir: &Path,
+    files: &[PathBuf],
+    output_dir: &Path,
+    format: OutputFormat,
+) -> Result<Vec<PathBuf>, String> {
+    if output_dir.exists() && !output_dir.is_dir() {
+        return Err(format!(
+            "{:?}: output must be a directory when input is a directory",
+            output_dir
+        ));
+    }
+    let mut outputs: Vec<PathBuf> = Vec::with_capacity(files.len());
+    let mut inputs = HashMap::new();
+    for file in files {
+        let relative = file.strip_prefix(input_dir).unwrap_or(file);
+        let path = output_file(output_dir, relative, format);
+        if let Some(other) = inputs.insert(path.clone(), file) {
+            return Err(format!(
+                "{:?} and {:?} would both be decoded to {:?}",
+                other, file, path
+            ));
+        }
+        outputs.push(path);
+    }
+    std::fs::create_dir_all(output_dir).map_err(|e| format!("{:?}: {}", output_dir, e))?;
+    // 子目录中的文件
+    for path in &outputs {
+        let dir = path.parent().unwrap_or(output_dir);
+        std::fs::create_dir_all(dir).map_err(|e| format!("{:?}: {}", dir, e))?;
+    }
+    Ok(outputs)
+}
+
+/// 读取整个输入文件, `-` 时读取标准输入
+fn read_input(path: &Path) -> io::Result<Vec<u8>> {
+    if Path::new(decode::STDIO_PATH) == path {
+        let mut input = Vec::new();
+        io::stdin().lock().read_to_end(&mut input)?;
+        return Ok(input);
+    }
+    std::fs::read(path)
+}
+
 fn main() {
     let args = Cli::parse();
     args.execute();
@@ -0,0 +1,359 @@
+use serde::Serialize;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::block::XlogBlock;
+use crate::decode::Decoder;
+use crate::error::DecodeError;
+use crate::filter::RecordFilter;
+use crate::record::{self, LogEntry, LogRecord};
+
+/// 解码结果的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// 与 python 脚本一致的纯文本
+    #[default]
+    Text,
+    /// 每行一个 JSON 对象 (JSON Lines / NDJSON)
+    Jsonl,
+    Csv,
+    Tsv,
+}
+
+impl OutputFormat {
+    /// 输出文件的扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "xlog.log",
+            OutputFormat::Jsonl => "xlog.jsonl",
+            OutputFormat::Csv => "xlog.csv",
+            OutputFormat::Tsv => "xlog.tsv",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "log" => Ok(OutputFormat::Text),
+            "jsonl" | "ndjson" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            _ => Err(format!("unsupported output format {:?}", s)),
+        }
+    }
+}
+
+/// csv/tsv 的表头, 与 `RecordRow` 的字段顺序一致
+pub const CSV_HEADERS: [&str; 12] = [
+    "source",
+    "seq",
+    "level",
+    "time",
+    "tag",
+    "pid",
+    "tid",
+    "main_thread",
+    "file",
+    "line",
+    "func",
+    "message",
+];
+
+/// 结构化输出的一行, 无法解析日志头的内容只有 `message`
+#[derive(Debug, Serialize)]
+pub struct RecordRow<'a> {
+    pub source: &'a str,
+    pub seq: u16,
+    pub level: Option<&'static str>,
+    pub time: Option<String>,
+    pub tag: Option<&'a str>,
+    pub pid: Option<i64>,
+    pub tid: Option<i64>,
+    pub main_thread: Option<bool>,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub func: Option<&'a str>,
+    pub message: &'a str,
+}
+
+impl<'a> RecordRow<'a> {
+    pub fn new(source: &'a str, block: &XlogBlock, entry: &'a LogEntry) -> RecordRow<'a> {
+        match entry {
+            LogEntry::Record(record) => RecordRow::from_record(source, block, record),
+            LogEntry::Text(message) => RecordRow {
+                source,
+                seq: block.seq,
+                level: None,
+                time: None,
+                tag: None,
+                pid: None,
+                tid: None,
+                main_thread: None,
+                file: None,
+                line: None,
+                func: None,
+                message,
+            },
+        }
+    }
+
+    fn from_record(source: &'a str, block: &XlogBlock, record: &'a LogRecord) -> RecordRow<'a> {
+        RecordRow {
+            source,
+            seq: block.seq,
+            level: Some(record.level.as_str()),
+            time: Some(record.time.to_rfc3339()),
+            tag: Some(&record.tag),
+            pid: Some(record.pid),
+            tid: Some(record.tid),
+            main_thread: Some(record.is_main_thread),
+            file: Some(&record.file),
+            line: Some(record.line),
+            func: Some(&record.func),
+            message: &record.message,
+        }
+    }
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+/// 将解析后的日志按结构化格式写出
+pub struct RecordWriter<W: Write> {
+    sink: Sink<W>,
+    format: OutputFormat,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// csv/tsv 格式会立即写出 BOM 和表头, 否则 Excel 会按本地编码打开中文内容
+    pub fn new(inner: W, format: OutputFormat) -> io::Result<RecordWriter<W>> {
+        let sink = match format {
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let mut inner = inner;
+                inner.write_all("\u{feff}".as_bytes())?;
+                let mut writer = csv::WriterBuilder::new()
+                    .delimiter(if OutputFormat::Tsv == format {
+                        b'\t'
+                    } else {
+                        b','
+                    })
+                    .has_headers(false)
+                    .from_writer(inner);
+                writer.write_record(CSV_HEADERS)?;
+                Sink::Csv(Box::new(writer))
+            }
+            _ => Sink::Plain(inner),
+        };
+        Ok(RecordWriter { sink, format })
+    }
+
+    /// 写出一个 block 中解析出的日志, `source` 为来源文件名
+    pub fn write_block(
+        &mut self,
+        source: &str,
+        block: &XlogBlock,
+        entries: &[LogEntry],
+    ) -> io::Result<()> {
+        for entry in entries {
+            match &mut self.sink {
+                Sink::Plain(inner) if OutputFormat::Jsonl == self.format => {
+                    let row = RecordRow::new(source, block, entry);
+                    serde_json::to_writer(&mut *inner, &row)?;
+                    inner.write_all(b"\n")?;
+                }
+                Sink::Plain(inner) => writeln!(inner, "{}", display_entry(entry))?,
+                Sink::Csv(writer) => writer.serialize(RecordRow::new(source, block, entry))?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(inner) => inner.flush(),
+            Sink::Csv(writer) => writer.flush(),
+        }
+    }
+
+    pub fn into_inner(self) -> io::Result<W> {
+        match self.sink {
+            Sink::Plain(inner) => Ok(inner),
+            Sink::Csv(writer) => writer.into_inner().map_err(|e| e.into_error()),
+        }
+    }
+}
+
+fn display_entry(entry: &LogEntry) -> String {
+    match entry {
+        LogEntry::Record(record) => record.to_string(),
+        LogEntry::Text(text) => text.clone(),
+    }
+}
+
+/// 解码 `input` 并按 `format` 写出, 只保留 `filter` 匹配的日志
+///
+/// `Text` 格式且没有过滤条件时与 `Decoder::decode` 的输出一致, 边解压边写出;
+/// 其余情况需要按 block 解析日志, 每个 block 的内容会完整解压到内存中
+pub fn write_records<W: Write>(
+    decoder: &mut Decoder,
+    input: &[u8],
+    source: &str,
+    format: OutputFormat,
+    filter: &RecordFilter,
+    output: W,
+) -> Result<(), DecodeError> {
+    if OutputFormat::Text == format && filter.is_empty() {
+        let mut output = output;
+        return decoder.decode(input, &mut output);
+    }
+
+    let mut writer = RecordWriter::new(output, format)?;
+    decoder.for_each_block(input, |block, decoded| {
+        let mut entries = record::parse_block(decoded);
+        entries.retain(|it| filter.matches(it));
+        writer.write_block(source, block, &entries)?;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::encode::{WriterConfig, XlogWriter};
+
+    #[test]
+    fn jsonl_test() {
+        let mut writer = XlogWriter::new(Vec::new(), WriterConfig::default()).unwrap();
+        writer.write_log(15, b"get mmap time: 1\n").unwrap();
+        writer
+            .write_log(
+                15,
+                b"[I][2022-01-10 +8.0 15:42:49.001][4983, 1*][app][main.cpp:10, main][hello\n\"world\"\n",
+            )
+            .unwrap();
+        let input = writer.finish().unwrap();
+
+        let mut output = Vec::new();
+        write_records(
+            &mut Decoder::new(""),
+            &input,
+            "app.xlog",
+            OutputFormat::Jsonl,
+            &RecordFilter::default(),
+            &mut output,
+        )
+        .unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|it| serde_json::from_str(it).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "get mmap time: 1");
+        assert!(lines[0]["level"].is_null());
+
+        let record = &lines[1];
+        assert_eq!(record["source"], "app.xlog");
+        assert_eq!(record["seq"], 1);
+        assert_eq!(record["level"], "I");
+        assert_eq!(record["time"], "2022-01-10T15:42:49.001+08:00");
+        assert_eq!(record["tag"], "app");
+        assert_eq!(record["pid"], 4983);
+        assert_eq!(record["tid"], 1);
+        assert_eq!(record["main_thread"], true);
+        assert_eq!(record["file"], "main.cpp");
+        assert_eq!(record["line"], 10);
+        assert_eq!(record["func"], "main");
+        assert_eq!(record["message"], "hello\n\"world\"");
+    }
+
+    #[test]
+    fn csv_test() {
+        let mut writer = XlogWriter::new(Vec::new(), WriterConfig::default()).unwrap();
+        writer
+            .write_log(
+                15,
+                b"[E][2022-01-10 +8.0 15:42:49.001][4983, 7][app][main.cpp:11, main][a, \"b\"\n\tc\n",
+            )
+            .unwrap();
+        let input = writer.finish().unwrap();
+
+        for (format, delimiter) in [(OutputFormat::Csv, b','), (OutputFormat::Tsv, b'\t')] {
+            let mut output = Vec::new();
+            write_records(
+                &mut Decoder::new(""),
+                &input,
+                "app.xlog",
+                format,
+                &RecordFilter::default(),
+                &mut output,
+            )
+            .unwrap();
+            assert!(output.starts_with("\u{feff}".as_bytes()));
+
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .from_reader(&output[3..]);
+            assert_eq!(reader.headers().unwrap(), CSV_HEADERS.as_ref());
+            let rows: Vec<csv::StringRecord> = reader.records().map(|it| it.unwrap()).collect();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(
+                rows[0].iter().collect::<Vec<_>>(),
+                [
+                    "app.xlog",
+                    "1",
+                    "E",
+                    "2022-01-10T15:42:49.001+08:00",
+                    "app",
+                    "4983",
+                    "7",
+                    "false",
+                    "main.cpp",
+                    "11",
+                    "main",
+                    "a, \"b\"\n\tc",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn text_filter_test() {
+        let logs = [
+            "get mmap time: 1\n",
+            "[I][2022-01-10 +8.0 14:00:00.000][1, 1*][net][a.cpp:1, f][connect\n",
+            "[E][2022-01-10 +8.0 14:10:00.000][1, 2][net][a.cpp:2, f][timeout\nretry 1\n",
+        ];
+        let mut writer = XlogWriter::new(Vec::new(), WriterConfig::default()).unwrap();
+        for log in &logs {
+            writer.write_log(14, log.as_bytes()).unwrap();
+        }
+        let input = writer.finish().unwrap();
+
+        let filter = RecordFilter {
+            level: Some(crate::record::LogLevel::Error),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        write_records(
+            &mut Decoder::new(""),
+            &input,
+            "app.xlog",
+            OutputFormat::Text,
+            &filter,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), logs[2]);
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// 与 mars `TLogLevel` 对应
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Verbose,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl LogLevel {
+    /// mars `levelStrings` 中的缩写
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Verbose => "V",
+            LogLevel::Debug => "D",
+            LogLevel::Info => "I",
+            LogLevel::Warn => "W",
+            LogLevel::Error => "E",
+            LogLevel::Fatal => "F",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    /// 同时支持缩写和全称, 忽略大小写
+    fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "v" | "verbose" => Ok(LogLevel::Verbose),
+            "d" | "debug" => Ok(LogLevel::Debug),
+            "i" | "info" => Ok(LogLevel::Info),
+            "w" | "warn" | "warning" => Ok(LogLevel::Warn),
+            "e" | "error" => Ok(LogLevel::Error),
+            "f" | "fatal" => Ok(LogLevel::Fatal),
+            _ => Err(format!("invalid log level {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 日志产生时的本地时间, 格式为 `2022-01-10 +8.0 12:00:00.123`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+    /// 相对 UTC 的偏移, mars 输出时保留一位小数的小时数
+    pub tz_offset_minutes: i32,
+}
+
+impl LogTime {
+    /// RFC 3339 格式, 例如 `2022-01-10T12:00:00.123+08:00`
+    pub fn to_rfc3339(&self) -> String {
+        let offset = self.tz_offset_minutes.abs();
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.millis,
+            if self.tz_offset_minutes < 0 { '-' } else { '+' },
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+impl FromStr for LogTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogTime, String> {
+        let invalid = || format!("invalid log time {:?}", s);
+        let mut parts = s.split(' ').filter(|it| !it.is_empty());
+        let (date, tz, time) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(date), Some(tz), Some(time), None) => (date, tz, time),
+            _ => return Err(invalid()),
+        };
+
+        let date: Vec<&str> = date.split('-').collect();
+        let (time, millis) = time.split_once('.').ok_or_else(invalid)?;
+        let time: Vec<&str> = time.split(':').collect();
+        if date.len() != 3 || time.len() != 3 {
+            return Err(invalid());
+        }
+        let tz_hours: f64 = tz.parse().map_err(|_| invalid())?;
+
+        Ok(LogTime {
+            year: date[0].parse().map_err(|_| invalid())?,
+            month: date[1].parse().map_err(|_| invalid())?,
+            day: date[2].parse().map_err(|_| invalid())?,
+            hour: time[0].parse().map_err(|_| invalid())?,
+            minute: time[1].parse().map_err(|_| invalid())?,
+            second: time[2].parse().map_err(|_| invalid())?,
+            millis: millis.parse().map_err(|_| invalid())?,
+            tz_offset_minutes: (tz_hours * 60.0).round() as i32,
+        })
+    }
+}
+
+impl fmt::Display for LogTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:+.1} {:02}:{:02}:{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            self.tz_offset_minutes as f64 / 60.0,
+            self.hour,
+            self.minute,
+            self.second,
+            self.millis
+        )
+    }
+}
+
+/// 一条 mars 格式化后的日志, 字段与 `XLoggerInfo` 对应
+///
+/// mars 的格式为 `[I][2022-01-10 +8.0 12:00:00.123][pid, tid*][tag][file:line, func][message`,
+/// tid 后的 `*` 表示主线程
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub time: LogTime,
+    pub pid: i64,
+    pub tid: i64,
+    pub is_main_thread: bool,
+    pub tag: String,
+    pub file: String,
+    pub line: u32,
+    pub func: String,
+    /// 日志内容, 多行日志以 `\n` 连接, 不包含末尾的换行
+    pub message: String,
+}
+
+impl LogRecord {
+    /// 解析一行日志的头部, 不是 mars 格式时返回 `None`
+    pub fn parse_line(line: &str) -> Option<LogRecord> {
+        let rest = line.strip_prefix('[')?;
+        let (level, rest) = rest.split_once("][")?;
+        let (time, rest) = rest.split_once("][")?;
+        let (thread, rest) = rest.split_once("][")?;
+        let (tag, rest) = rest.split_once("][")?;
+        let (location, message) = rest.split_once("][")?;
+
+        let (pid, tid) = thread.split_once(", ")?;
+        let (tid, is_main_thread) = match tid.strip_suffix('*') {
+            Some(tid) => (tid, true),
+            None => (tid, false),
+        };
+        let (file_line, func) = location.split_once(", ")?;
+        let (file, line_no) = file_line.rsplit_once(':')?;
+
+        Some(LogRecord {
+            level: level.parse().ok().filter(|_| level.len() == 1)?,
+            time: time.parse().ok()?,
+            pid: pid.parse().ok()?,
+            tid: tid.parse().ok()?,
+            is_main_thread,
+            tag: String::from(tag),
+            file: String::from(file),
+            line: line_no.parse().ok()?,
+            func: String::from(func),
+            message: String::from(message),
+        })
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}][{}][{}, {}{}][{}][{}:{}, {}][{}",
+            self.level,
+            self.time,
+            self.pid,
+            self.tid,
+            if self.is_main_thread { "*" } else { "" },
+            self.tag,
+            self.file,
+            self.line,
+            self.func,
+            self.message
+        )
+    }
+}
+
+/// 解码后的一段日志
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEntry {
+    Record(LogRecord),
+    /// 出现在第一条日志之前、无法归属的内容, 例如 mars 写入的文件头信息
+    Text(String),
+}
+
+/// 将解码后的日志拆分为 `LogEntry`, 不以日志头开始的行归入上一条日志
+pub fn parse_entries(text: &str) -> Vec<LogEntry> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        if let Some(record) = LogRecord::parse_line(line) {
+            entries.push(LogEntry::Record(record));
+            continue;
+        }
+        match entries.last_mut() {
+            Some(LogEntry::Record(LogRecord { message, .. })) | Some(LogEntry::Text(message)) => {
+                message.push('\n');
+                message.push_str(line);
+            }
+            None => entries.push(LogEntry::Text(String::from(line))),
+        }
+    }
+    entries
+}
+
+/// 解析 `Decoder::for_each_block` 输出的单个 block 内容
+pub fn parse_block(decoded: &[u8]) -> Vec<LogEntry> {
+    parse_entries(&String::from_utf8_lossy(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::decode::Decoder;
+    use crate::encode::{AppenderMode, WriterConfig, XlogWriter};
+
+    #[test]
+    fn parse_line_test() {
+        let line =
+            "[I][2022-01-10 +8.0 12:00:00.123][4983, 259*][net][Conn.cpp:42, Connect][connected";
+        let record = LogRecord::parse_line(line).unwrap();
+        assert_eq!(record.level, LogLevel::Info);
+        assert_eq!(
+            record.time,
+            LogTime {
+                year: 2022,
+                month: 1,
+                day: 10,
+                hour: 12,
+                minute: 0,
+                second: 0,
+                millis: 123,
+                tz_offset_minutes: 480,
+            }
+        );
+        assert_eq!(
+            (record.pid, record.tid, record.is_main_thread),
+            (4983, 259, true)
+        );
+        assert_eq!(record.tag, "net");
+        assert_eq!((record.file.as_str(), record.line), ("Conn.cpp", 42));
+        assert_eq!(record.func, "Connect");
+        assert_eq!(record.message, "connected");
+        assert_eq!(record.to_string(), line);
+
+        let line = "[W][2022-01-09 -3.5 17:03:10.005][1, 2][][ViewController.m:23, -[ViewController viewDidLoad]][a][b]";
+        let record = LogRecord::parse_line(line).unwrap();
+        assert_eq!(record.time.tz_offset_minutes, -210);
+        assert_eq!(record.time.to_rfc3339(), "2022-01-09T17:03:10.005-03:30");
+        assert!(!record.is_main_thread);
+        assert_eq!(record.tag, "");
+        assert_eq!(record.func, "-[ViewController viewDidLoad]");
+        assert_eq!(record.message, "a][b]");
+        assert_eq!(record.to_string(), line);
+
+        assert!(LogRecord::parse_line("get mmap time: 1").is_none());
+        assert!(
+            LogRecord::parse_line("[X][2022-01-10 +8.0 12:00:00.123][1, 2][t][f:1, g][m").is_none()
+        );
+    }
+
+    #[test]
+    fn parse_block_test() {
+        let logs = [
+            "^^^^^^^^^^May 20 2021^^^09:51:00^^^^^^^^^^\nget mmap time: 1\n",
+            "[D][2022-01-10 +8.0 15:42:49.001][4983, 1*][app][main.cpp:10, main][first\n",
+            "[E][2022-01-10 +8.0 15:42:49.002][4983, 7][app][main.cpp:11, main][multi\nline\nmessage\n",
+        ];
+        for mode in [AppenderMode::Async, AppenderMode::Sync] {
+            let config = WriterConfig {
+                mode,
+                ..Default::default()
+            };
+            let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+            for log in &logs {
+                writer.write_log(15, log.as_bytes()).unwrap();
+            }
+            let output = writer.finish().unwrap();
+
+            let mut entries = Vec::new();
+            Decoder::new("")
+                .for_each_block(&output, |_, decoded| {
+                    entries.extend(parse_block(decoded));
+                    Ok(())
+                })
+                .unwrap();
+
+            assert_eq!(entries.len(), 3);
+            assert_eq!(
+                entries[0],
+                LogEntry::Text(String::from(
+                    "^^^^^^^^^^May 20 2021^^^09:51:00^^^^^^^^^^\nget mmap time: 1"
+                ))
+            );
+            match (&entries[1], &entries[2]) {
+                (LogEntry::Record(first), LogEntry::Record(second)) => {
+                    assert_eq!(first.level, LogLevel::Debug);
+                    assert_eq!(first.message, "first");
+                    assert_eq!(second.level, LogLevel::Error);
+                    assert_eq!(second.message, "multi\nline\nmessage");
+                    assert_eq!(format!("{}\n", second), logs[2]);
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+}
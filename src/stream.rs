@@ -0,0 +1,178 @@
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+use crate::utils;
+
+/// 每次解密的字节数, 必须是 8 的整数倍
+const TEA_BUF_LEN: usize = 8 * 1024;
+
+/// 边读边解密 async crypt block 的 payload, 与 `utils::tea_encrypt_buf` 对应
+///
+/// 只有完整的 8 字节块被加密, 末尾不足 8 字节的部分原样输出
+pub(crate) struct TeaReader<'a> {
+    data: &'a [u8],
+    crypt_len: usize,
+    key: [u32; 4],
+    pos: usize,
+    buf: Box<[u8; TEA_BUF_LEN]>,
+    start: usize,
+    end: usize,
+}
+
+impl<'a> TeaReader<'a> {
+    pub(crate) fn new(data: &'a [u8], key: [u32; 4]) -> TeaReader<'a> {
+        TeaReader {
+            data,
+            crypt_len: data.len() - data.len() % 8,
+            key,
+            pos: 0,
+            buf: Box::new([0; TEA_BUF_LEN]),
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl<'a> BufRead for TeaReader<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.start == self.end && self.pos < self.data.len() {
+            let len = TEA_BUF_LEN.min(self.data.len() - self.pos);
+            self.buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            let crypt = self.crypt_len.saturating_sub(self.pos).min(len);
+            utils::tea_decrypt_buf(&mut self.buf[..crypt], &self.key);
+            self.pos += len;
+            self.start = 0;
+            self.end = len;
+        }
+        Ok(&self.buf[self.start..self.end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.start = (self.start + amt).min(self.end);
+    }
+}
+
+impl<'a> Read for TeaReader<'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(out.len());
+        out[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+/// 依次读出 legacy segmented block 中以 2 字节长度开头的每一段, 不复制数据
+///
+/// 调用前需要用 `check_segments` 确认数据完整
+pub(crate) struct SegmentReader<'a> {
+    data: &'a [u8],
+    segment: &'a [u8],
+}
+
+impl<'a> SegmentReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> SegmentReader<'a> {
+        SegmentReader { data, segment: &[] }
+    }
+}
+
+/// 检查每一段的长度, 数据被截断时返回出错段的起始位置
+pub(crate) fn check_segments(data: &[u8]) -> Result<(), usize> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = match data.get(pos..pos + 2) {
+            Some(it) => u16::from_le_bytes([it[0], it[1]]) as usize,
+            None => return Err(pos),
+        };
+        if pos + 2 + len > data.len() {
+            return Err(pos);
+        }
+        pos += 2 + len;
+    }
+    Ok(())
+}
+
+impl<'a> BufRead for SegmentReader<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.segment.is_empty() && self.data.len() >= 2 {
+            let len = u16::from_le_bytes([self.data[0], self.data[1]]) as usize;
+            let end = (2 + len).min(self.data.len());
+            self.segment = &self.data[2..end];
+            self.data = &self.data[end..];
+        }
+        Ok(self.segment)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.segment = &self.segment[amt.min(self.segment.len())..];
+    }
+}
+
+impl<'a> Read for SegmentReader<'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(out.len());
+        out[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+/// `copy` 出错的位置
+pub(crate) enum CopyError {
+    /// 读取 (解压) 出错
+    Read(io::Error),
+    /// 写入出错
+    Write(io::Error),
+}
+
+/// 与 `io::copy` 相同, 使用固定大小的缓冲区, 但区分读取和写入的错误
+pub(crate) fn copy<R: Read, W: Write>(reader: &mut R, output: &mut W) -> Result<u64, CopyError> {
+    let mut buf = [0; 8 * 1024];
+    let mut written = 0;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(CopyError::Read(e)),
+        };
+        output.write_all(&buf[..len]).map_err(CopyError::Write)?;
+        written += len as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tea_reader_test() {
+        let key = [1, 2, 3, 4];
+        for len in [0, 7, 8, 21, TEA_BUF_LEN, TEA_BUF_LEN + 13] {
+            let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let mut encrypted = plain.clone();
+            utils::tea_encrypt_buf(&mut encrypted, &key);
+
+            let mut decrypted = Vec::new();
+            TeaReader::new(&encrypted, key)
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, plain, "{}", len);
+        }
+    }
+
+    #[test]
+    fn segment_reader_test() {
+        let data = [2, 0, b'a', b'b', 0, 0, 3, 0, b'c', b'd', b'e'];
+        assert_eq!(check_segments(&data), Ok(()));
+        let mut output = String::new();
+        SegmentReader::new(&data)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "abcde");
+
+        assert_eq!(check_segments(&data[..9]), Err(6));
+        assert_eq!(check_segments(&data[..5]), Err(4));
+    }
+}
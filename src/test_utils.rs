@@ -0,0 +1,30 @@
+//! 各模块测试共用的 sample 数据及测试用 xlog 的构造
+
+use std::path::PathBuf;
+
+use crate::encode::{WriterConfig, XlogWriter};
+
+pub(crate) fn sample_data_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("sample_data")
+}
+
+/// `sample_data/custom.env` 中的私钥及公钥 hex
+pub(crate) fn load_sample_keys() -> (String, String) {
+    dotenv::from_path(sample_data_path().join("custom.env").as_path()).ok();
+    (
+        std::env::var("TEST_XLOG_PRIVATE_KEY").unwrap(),
+        std::env::var("TEST_XLOG_PUBLIC_KEY").unwrap(),
+    )
+}
+
+/// 每个小时写入一条 `hour {hour}` 日志并单独生成一个 block
+pub(crate) fn write_blocks<I: IntoIterator<Item = u8>>(config: WriterConfig, hours: I) -> Vec<u8> {
+    let mut writer = XlogWriter::new(Vec::new(), config).unwrap();
+    for hour in hours {
+        writer
+            .write_log(hour, format!("hour {}\n", hour).as_bytes())
+            .unwrap();
+        writer.flush_block().unwrap();
+    }
+    writer.finish().unwrap()
+}
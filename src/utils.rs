@@ -0,0 +1,152 @@
+use std::fmt::Write;
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        write!(&mut s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+/// 标准 base64, 带 `=` 填充
+pub fn encode_base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+pub fn tea_decrypt(v: &mut [u32], k: &[u32]) {
+    let mut v0 = v[0];
+    let mut v1 = v[1];
+    let delta: u32 = 0x9e3779b9;
+    let total_sum: u32 = 0x9e3779b9 << 4;
+
+    let mut sum: u32 = total_sum;
+    let k0 = k[0];
+    let k1 = k[1];
+    let k2 = k[2];
+    let k3 = k[3];
+    for _ in 0..16 {
+        let t1 =
+            ((v0 << 4).wrapping_add(k2)) ^ (v0.wrapping_add(sum)) ^ ((v0 >> 5).wrapping_add(k3));
+        v1 = v1.wrapping_sub(t1);
+
+        let t0 =
+            ((v1 << 4).wrapping_add(k0)) ^ (v1.wrapping_add(sum)) ^ ((v1 >> 5).wrapping_add(k1));
+        v0 = v0.wrapping_sub(t0);
+
+        sum = sum.wrapping_sub(delta);
+    }
+    v[0] = v0;
+    v[1] = v1;
+}
+
+pub fn tea_encrypt(v: &mut [u32], k: &[u32]) {
+    let mut v0 = v[0];
+    let mut v1 = v[1];
+    let delta: u32 = 0x9e3779b9;
+
+    let mut sum: u32 = 0;
+    let k0 = k[0];
+    let k1 = k[1];
+    let k2 = k[2];
+    let k3 = k[3];
+    for _ in 0..16 {
+        sum = sum.wrapping_add(delta);
+
+        let t0 =
+            ((v1 << 4).wrapping_add(k0)) ^ (v1.wrapping_add(sum)) ^ ((v1 >> 5).wrapping_add(k1));
+        v0 = v0.wrapping_add(t0);
+
+        let t1 =
+            ((v0 << 4).wrapping_add(k2)) ^ (v0.wrapping_add(sum)) ^ ((v0 >> 5).wrapping_add(k3));
+        v1 = v1.wrapping_add(t1);
+    }
+    v[0] = v0;
+    v[1] = v1;
+}
+
+/// 与 mars `LogCrypt::CryptAsyncLog` 一致, 只加密完整的 8 字节块, 末尾不足 8 字节的部分保持明文
+pub fn tea_encrypt_buf(buf: &mut [u8], k: &[u32]) {
+    for chunk in buf.chunks_exact_mut(8) {
+        let mut v = [
+            u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+            u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+        ];
+        tea_encrypt(&mut v, k);
+        chunk[..4].copy_from_slice(&v[0].to_le_bytes());
+        chunk[4..].copy_from_slice(&v[1].to_le_bytes());
+    }
+}
+
+/// `tea_encrypt_buf` 的逆运算
+pub fn tea_decrypt_buf(buf: &mut [u8], k: &[u32]) {
+    for chunk in buf.chunks_exact_mut(8) {
+        let mut v = [
+            u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+            u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+        ];
+        tea_decrypt(&mut v, k);
+        chunk[..4].copy_from_slice(&v[0].to_le_bytes());
+        chunk[4..].copy_from_slice(&v[1].to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tea_round_trip_test() {
+        let key = [0x01234567, 0x89abcdef, 0xfedcba98, 0x76543210];
+        let plain = [0xdeadbeef, 0x0badf00d];
+
+        let mut v = plain;
+        tea_encrypt(&mut v, &key);
+        assert_ne!(v, plain);
+        tea_decrypt(&mut v, &key);
+        assert_eq!(v, plain);
+    }
+
+    #[test]
+    fn tea_encrypt_buf_tail_test() {
+        let key = [1, 2, 3, 4];
+        let plain: Vec<u8> = (0..21).collect();
+
+        let mut buf = plain.clone();
+        tea_encrypt_buf(&mut buf, &key);
+        assert_ne!(buf[..16], plain[..16]);
+        assert_eq!(buf[16..], plain[16..]);
+
+        tea_decrypt_buf(&mut buf, &key);
+        assert_eq!(buf, plain);
+    }
+
+    #[test]
+    fn encode_hex_test() {
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x10]), "00ff10");
+    }
+
+    #[test]
+    fn encode_base64_test() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "+/8=");
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt;
+use std::io;
+
+use crate::block::{self, BlockIter, SeqGap};
+use crate::decode::{DecodeOptions, Decoder};
+use crate::error::DecodeError;
+use crate::keyring::{KeyUsage, Keyring};
+
+/// 校验结果, 按严重程度排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum VerifyStatus {
+    #[default]
+    Ok,
+    /// 数据损坏: 需要跳过的字节, 截断的 block, 无法解压的 block
+    Corrupt,
+    /// 私钥不合法, 不匹配, 或加密的文件没有提供私钥
+    WrongKey,
+    /// 找不到任何合法的 block
+    NotXlog,
+}
+
+impl VerifyStatus {
+    /// 命令行的退出码, 1 留给无法读取文件等其他错误
+    pub fn exit_code(self) -> i32 {
+        match self {
+            VerifyStatus::Ok => 0,
+            VerifyStatus::Corrupt => 2,
+            VerifyStatus::WrongKey => 3,
+            VerifyStatus::NotXlog => 4,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Corrupt => "corrupt",
+            VerifyStatus::WrongKey => "wrong key",
+            VerifyStatus::NotXlog => "not an xlog",
+        }
+    }
+
+    fn from_error(error: &DecodeError) -> VerifyStatus {
+        match error {
+            DecodeError::NotXlog { .. } => VerifyStatus::NotXlog,
+            DecodeError::InvalidKey { .. }
+            | DecodeError::WrongKey { .. }
+            | DecodeError::NoMatchingKey { .. } => VerifyStatus::WrongKey,
+            _ => VerifyStatus::Corrupt,
+        }
+    }
+}
+
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 校验过程中发现的一个问题
+#[derive(Debug)]
+pub enum Issue {
+    /// 跳过 `skipped` 字节损坏的数据后, 在 `offset` 处找到下一个合法 block
+    Resync { offset: usize, skipped: usize },
+    /// seq 不连续, 通常是日志被丢弃而不是文件损坏, 不影响校验结果
+    MissingSeq(SeqGap),
+    /// 加密变体的 block, 但没有提供私钥
+    MissingKey { offset: usize, seq: u16 },
+    /// 解密解压失败, 截断的 block 或末尾的垃圾数据
+    Error(DecodeError),
+}
+
+impl Issue {
+    pub fn status(&self) -> VerifyStatus {
+        match self {
+            Issue::Resync { .. } => VerifyStatus::Corrupt,
+            Issue::MissingSeq(_) => VerifyStatus::Ok,
+            Issue::MissingKey { .. } => VerifyStatus::WrongKey,
+            Issue::Error(e) => VerifyStatus::from_error(e),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Resync { offset, skipped } => write!(
+                f,
+                "skipped {} corrupt bytes before block at offset {}",
+                skipped, offset
+            ),
+            Issue::MissingSeq(it) => write!(
+                f,
+                "log seq {}-{} is missing before block at offset {}",
+                it.expected,
+                it.last(),
+                it.offset
+            ),
+            Issue::MissingKey { offset, seq } => write!(
+                f,
+                "block at offset {} (seq {}) is encrypted but no private key given",
+                offset, seq
+            ),
+            Issue::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// 一个文件的校验结果
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// 找到的合法 block 数量
+    pub blocks: usize,
+    /// 按在文件中出现的顺序
+    pub issues: Vec<Issue>,
+    /// 加密 block 的 client 公钥及解密使用的私钥
+    pub keys: Vec<KeyUsage>,
+}
+
+impl VerifyReport {
+    /// 所有问题中最严重的一个
+    pub fn status(&self) -> VerifyStatus {
+        self.issues
+            .iter()
+            .map(Issue::status)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// 完整解码 `input` 但不输出内容, 某个 block 出错时继续校验之后的 block
+pub fn verify(input: &[u8], private_key: &str) -> VerifyReport {
+    verify_keyring(input, Keyring::single(private_key))
+}
+
+/// 与 `verify` 相同, 使用密钥环中能解密的私钥
+pub fn verify_keyring(input: &[u8], keyring: Keyring) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let decoder = Decoder::with_keyring(keyring, DecodeOptions::default());
+    if let Err(e) = decoder.check_key() {
+        report.issues.push(Issue::Error(e));
+        return report;
+    }
+
+    let mut last_seq = 0;
+    for block in BlockIter::new(input) {
+        let block = match block {
+            Ok(it) => it,
+            Err(e) => {
+                report.issues.push(Issue::Error(e));
+                break;
+            }
+        };
+        report.blocks += 1;
+        if block.skipped > 0 {
+            report.issues.push(Issue::Resync {
+                offset: block.offset,
+                skipped: block.skipped,
+            });
+        }
+        if let Some(missing) = block::seq_gap(&mut last_seq, &block) {
+            report.issues.push(Issue::MissingSeq(missing));
+        }
+        KeyUsage::count(&mut report.keys, &block);
+
+        if !decoder.has_key() && block.crypt {
+            report.issues.push(Issue::MissingKey {
+                offset: block.offset,
+                seq: block.seq,
+            });
+            continue;
+        }
+        if let Err(e) = decoder.decode_block_into(input, &block, &mut io::sink()) {
+            report.issues.push(Issue::Error(e));
+        }
+    }
+    decoder.resolve_keys(&mut report.keys);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::encode::{AppenderMode, CompressMode, WriterConfig};
+    use crate::test_utils::{load_sample_keys, write_blocks};
+
+    #[test]
+    fn verify_test() {
+        // zstd 帧头损坏时一定解压失败, zlib 可能解出部分内容
+        let config = WriterConfig {
+            compress_mode: CompressMode::Zstd,
+            ..Default::default()
+        };
+        let input = write_blocks(config, [14, 15, 16]);
+        let report = verify(&input, "");
+        assert_eq!(report.blocks, 3);
+        assert!(report.issues.is_empty());
+        assert_eq!(report.status(), VerifyStatus::Ok);
+
+        let report = verify(&[0xff; 64], "");
+        assert_eq!(report.status(), VerifyStatus::NotXlog);
+        assert_eq!(report.status().exit_code(), 4);
+
+        // 在开头和末尾加入垃圾数据, 并损坏第二个 block 的 zstd 帧头
+        let blocks: Vec<_> = block::blocks(&input).map(|it| it.unwrap()).collect();
+        let mut corrupted = vec![0xff; 3];
+        corrupted.extend_from_slice(&input);
+        let payload = blocks[1].payload.start + 3;
+        corrupted[payload..payload + 4].copy_from_slice(&[0xff; 4]);
+        corrupted.extend_from_slice(&[0xee; 8]);
+
+        let report = verify(&corrupted, "");
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.status(), VerifyStatus::Corrupt);
+        assert_eq!(report.status().exit_code(), 2);
+        assert_eq!(report.issues.len(), 3, "{:?}", report.issues);
+        assert!(matches!(
+            report.issues[0],
+            Issue::Resync {
+                offset: 3,
+                skipped: 3
+            }
+        ));
+        assert!(matches!(
+            report.issues[1],
+            Issue::Error(DecodeError::Decompress { seq, .. }) if seq == blocks[1].seq
+        ));
+        assert!(matches!(
+            report.issues[2],
+            Issue::Error(DecodeError::NoValidBlock { remaining: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn verify_truncated_zstd_test() {
+        let config = WriterConfig {
+            compress_mode: CompressMode::Zstd,
+            ..Default::default()
+        };
+        let input = write_blocks(config, [14, 15, 16]);
+
+        // 第二个 block 的 zstd 帧截去一半, 同时修改长度使 block 本身仍然完整
+        let blocks: Vec<_> = block::blocks(&input).map(|it| it.unwrap()).collect();
+        let block = &blocks[1];
+        let payload = &input[block.payload.clone()];
+        let truncated = &payload[..payload.len() / 2];
+        let length = block.offset + block.magic.header_len() - block.magic.crypt_key_len() - 4;
+        let mut corrupted = input[..block.payload.start].to_vec();
+        corrupted[length..length + 4].copy_from_slice(&(truncated.len() as u32).to_le_bytes());
+        corrupted.extend_from_slice(truncated);
+        corrupted.extend_from_slice(&input[block.payload.end..]);
+
+        let report = verify(&corrupted, "");
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.status(), VerifyStatus::Corrupt);
+        assert!(matches!(
+            report.issues[..],
+            [Issue::Error(DecodeError::Decompress { seq, .. })] if seq == block.seq
+        ));
+    }
+
+    #[test]
+    fn verify_sync_crypt_test() {
+        // 设置了公钥的同步模式使用加密变体的 magic, 但 payload 是明文, 不需要私钥
+        let (_, public_key) = load_sample_keys();
+        for compress_mode in [CompressMode::Zlib, CompressMode::Zstd] {
+            let config = WriterConfig {
+                mode: AppenderMode::Sync,
+                compress_mode,
+                pub_key: public_key.clone(),
+                ..Default::default()
+            };
+            let input = write_blocks(config, [14, 15, 16]);
+            let first = block::blocks(&input).next().unwrap().unwrap();
+            assert!(first.magic.is_crypt_variant() && !first.crypt);
+
+            let report = verify(&input, "");
+            assert_eq!(report.blocks, 3);
+            assert!(report.issues.is_empty(), "{:?}", report.issues);
+            assert_eq!(report.status(), VerifyStatus::Ok);
+        }
+    }
+
+    #[test]
+    fn verify_key_test() {
+        let (private_key, public_key) = load_sample_keys();
+        let config = WriterConfig {
+            compress_mode: CompressMode::Zstd,
+            pub_key: public_key,
+            ..Default::default()
+        };
+        let input = write_blocks(config, [14, 15, 16]);
+        assert_eq!(verify(&input, &private_key).status(), VerifyStatus::Ok);
+
+        let report = verify(&input, "");
+        assert_eq!(report.status(), VerifyStatus::WrongKey);
+        assert_eq!(report.issues.len(), 3);
+
+        let report = verify(&input, "not hex");
+        assert!(matches!(
+            report.issues[..],
+            [Issue::Error(DecodeError::InvalidKey { .. })]
+        ));
+        assert_eq!(report.status().exit_code(), 3);
+
+        let wrong_key = micro_uecc_safe::KeyPair::generate().unwrap();
+        let report = verify(&input, &wrong_key.private_key.to_hex());
+        assert_eq!(report.status(), VerifyStatus::WrongKey);
+        assert!(report
+            .issues
+            .iter()
+            .all(|it| matches!(it, Issue::Error(DecodeError::WrongKey { .. }))));
+    }
+}